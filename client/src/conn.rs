@@ -2,71 +2,505 @@ use async_std::future::timeout;
 use async_std::net::UdpSocket;
 use async_std::sync::Arc;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc;
 use log::*;
-use shared::handshake::HandshakeMessage;
-use shared::packet::Packet;
+use shared::ack::{AckTracker, Delivery};
+use shared::channel::{Channel, Channels};
+use shared::clock::ClockSync;
+use shared::codec::{Codec, CodecKind, DecodeLimits};
+use shared::credentials::Credentials;
+use shared::crypto::Cipher;
+use shared::handshake::{
+    Cookie, FailureReason, HandshakeMessage, KeyExchange, Proof, ResumeToken, Role,
+};
+use shared::outbox::Outbox;
+use shared::packet::{
+    Chunk, ConnectionId, HeaderFormat, Packet, PacketError, Reassembler, SequenceNumber,
+    TimestampBounds, PROTOCOL_VERSION,
+};
 use shared::proto::Message;
+use shared::stats::{ConnectionStats, StatsSnapshot};
+use shared::window::ReplayWindow;
 use shared::{hexdump, Result};
 use snafu::Snafu;
+use std::collections::VecDeque;
+use std::fmt;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-#[derive(Snafu, Debug)]
-enum ConnError {
+/// Why `Conn::connect` failed, for callers to tell apart by downcasting
+#[derive(Snafu, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnError {
     #[snafu(display("handshake failure"))]
     HandshakeFailure,
+    #[snafu(display(
+        "server does not support protocol version {}, it speaks version {}",
+        client,
+        server
+    ))]
+    VersionMismatch { server: u8, client: u8 },
+    #[snafu(display("server is full"))]
+    ServerFull,
+    #[snafu(display("banned from the server"))]
+    Banned,
+    #[snafu(display("server rejected our credentials"))]
+    BadCredentials,
+    #[snafu(display("key exchange failed"))]
+    BadChallenge,
+    #[snafu(display("no answer from the server"))]
+    HandshakeTimeout,
+}
+
+impl ConnError {
+    /// Whether connecting again would fail the same way, so that there is no point retrying
+    pub fn is_permanent(self) -> bool {
+        match self {
+            ConnError::VersionMismatch { .. } | ConnError::Banned | ConnError::BadCredentials => {
+                true
+            }
+            ConnError::HandshakeFailure
+            | ConnError::ServerFull
+            | ConnError::BadChallenge
+            | ConnError::HandshakeTimeout => false,
+        }
+    }
+}
+
+impl From<FailureReason> for ConnError {
+    fn from(reason: FailureReason) -> ConnError {
+        match reason {
+            FailureReason::ServerFull => ConnError::ServerFull,
+            FailureReason::VersionMismatch { server } => ConnError::VersionMismatch {
+                server,
+                client: PROTOCOL_VERSION,
+            },
+            FailureReason::Banned => ConnError::Banned,
+            FailureReason::BadCredentials => ConnError::BadCredentials,
+            FailureReason::BadChallenge => ConnError::BadChallenge,
+        }
+    }
+}
+
+/// Time without hearing from the server after which the connection is considered lost. The
+/// server sends a refresh every frame and answers our heartbeats.
+pub const SERVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why the connection to the server was lost
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Nothing was heard from the server for `SERVER_TIMEOUT`
+    Timeout,
+    /// The server sent `Disconnect`
+    Closed,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::Closed => "closed by the server",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Message(Message),
+    /// The connection is lost, after which no more events follow
+    Disconnected {
+        reason: DisconnectReason,
+    },
+}
+
+/// Error for a message the server sent instead of the next step of the handshake
+fn refusal(message: Message) -> ConnError {
+    match message {
+        Message::Handshake(HandshakeMessage::Failure(reason)) => reason.into(),
+        other => {
+            warn!("unexpected {:?} during handshake", other);
+            ConnError::HandshakeFailure
+        }
+    }
+}
+
+/// Time `Conn::connect` waits for each step of the handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval at which the handshake message we wait for an answer to is retransmitted
+const HANDSHAKE_RETRY: Duration = Duration::from_millis(500);
+
+/// Time `Conn::close` waits for the server to acknowledge our `Disconnect`
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Interval at which `Conn::close` retransmits `Disconnect`, unless an ack arrives first
+const CLOSE_POLL: Duration = Duration::from_millis(100);
+
+/// What it takes to pick up a session again after losing the connection, see `Conn::resumption`.
+/// The server hands back the game state of the session, while the new connection starts its
+/// sequence numbers, channels and keys afresh with the handshake.
+#[derive(Debug, Clone)]
+pub struct Resumption {
+    token: ResumeToken,
 }
 
 pub struct Conn {
-    server_sequence: Arc<AtomicU32>,
+    window: Mutex<ReplayWindow>,
     client_sequence: Arc<AtomicU32>,
+    acks: Mutex<AckTracker>,
+    delivery_tx: Mutex<Option<mpsc::UnboundedSender<Delivery>>>,
+    channels: Mutex<Channels>,
+    reassembler: Mutex<Reassembler>,
+    outbox: Mutex<Outbox>,
+    header_format: HeaderFormat,
+    /// Codec for our messages, which is `Bincode` until the server names the one it picked in
+    /// its success
+    codec: Mutex<CodecKind>,
+    /// Size limits for messages from the server
+    decode_limits: DecodeLimits,
+    /// Assigned by the server in its challenge, identifies us even if our address changes
+    connection_id: ConnectionId,
+    /// Set once keys have been derived, after which incoming packets must be encrypted
+    cipher: Mutex<Option<Cipher>>,
+    /// Whether outgoing packets are encrypted, which starts once the server has proven that it
+    /// derived the same keys. Until then the server cannot decrypt them.
+    encrypt: AtomicBool,
+    /// Whether packets have arrived since we last sent one, which would carry acks for them
+    ack_pending: AtomicBool,
+    /// Token for resuming the session, handed out by the server on success
+    resume_token: Option<ResumeToken>,
+    /// When a packet from the server was last accepted
+    last_heard: Mutex<Instant>,
+    stats: Mutex<ConnectionStats>,
+    clock: Mutex<ClockSync>,
+    timestamp_bounds: TimestampBounds,
+    /// Messages received but not yet returned from `next_event`
+    inbox: Mutex<VecDeque<Message>>,
     socket: UdpSocket,
     remote: SocketAddr,
 }
 
 impl Conn {
-    pub async fn connect(remote: SocketAddr) -> Result<Conn> {
+    pub async fn connect(
+        remote: SocketAddr,
+        header_format: HeaderFormat,
+        codec: CodecKind,
+        credentials: Credentials,
+        resume: Option<Resumption>,
+    ) -> Result<Conn> {
         let socket = UdpSocket::bind("127.0.0.1:0").await?;
         trace!("socket created");
-        let conn = Conn {
-            server_sequence: Arc::new(AtomicU32::new(0)),
+        let mut conn = Conn {
+            window: Mutex::new(ReplayWindow::new()),
             client_sequence: Arc::new(AtomicU32::new(1)),
+            acks: Mutex::new(AckTracker::new()),
+            delivery_tx: Mutex::new(None),
+            channels: Mutex::new(Channels::new()),
+            reassembler: Mutex::new(Reassembler::default()),
+            outbox: Mutex::new(Outbox::new()),
+            header_format,
+            codec: Mutex::new(CodecKind::Bincode),
+            decode_limits: DecodeLimits::default(),
+            connection_id: ConnectionId::NONE,
+            cipher: Mutex::new(None),
+            encrypt: AtomicBool::new(false),
+            ack_pending: AtomicBool::new(false),
+            resume_token: None,
+            last_heard: Mutex::new(Instant::now()),
+            stats: Mutex::new(ConnectionStats::new()),
+            clock: Mutex::new(ClockSync::new()),
+            timestamp_bounds: TimestampBounds::default(),
+            inbox: Mutex::new(VecDeque::new()),
             socket,
             remote,
         };
 
-        // First send connect message
+        // First ask for a cookie, then connect with it
         trace!("sending connect msg");
-        conn.send(Message::Connect).await?;
+        let connect = Message::Connect {
+            cookie: Cookie::default(),
+        };
+        let cookie = match conn.request(connect, |_| false).await? {
+            Message::Cookie(cookie) => cookie,
+            other => return Err(refusal(other).into()),
+        };
 
         trace!("sent connect msg");
 
-        // Now we should receive a challenge nonce
-        match timeout(Duration::from_secs(5), conn.next_message()).await? {
-            Message::Handshake(HandshakeMessage::Challenge(nonce)) => {
-                conn.send(Message::Handshake(HandshakeMessage::Challenge(nonce)))
-                    .await?;
+        // Now we should receive the server's public key, skipping duplicate cookies
+        let reply = conn
+            .request(Message::Connect { cookie }, |reply| {
+                matches!(reply, Message::Cookie(_))
+            })
+            .await?;
+        let keys = match reply {
+            Message::Handshake(HandshakeMessage::Challenge {
+                public_key,
+                connection_id,
+            }) => {
+                conn.connection_id = connection_id;
+                let exchange = KeyExchange::new();
+                let keys = exchange.derive(Role::Client, &public_key);
+                conn.send_reliable(Message::Handshake(HandshakeMessage::Response {
+                    public_key: exchange.public_key(),
+                    proof: keys.proof(),
+                    credentials: keys
+                        .seal_credentials(&credentials, resume.map(|resume| resume.token)),
+                    codec,
+                }))?;
+                conn.flush().await?;
+                // the server answers with encrypted packets once it has checked our proof
+                *conn.cipher.lock().unwrap() = Some(keys.cipher());
+                keys
             }
-            _ => return Err(ConnError::HandshakeFailure.into()),
+            other => return Err(refusal(other).into()),
+        };
+        let (proof, resume_token) = conn.recv_success().await?;
+        if !keys.verify(&proof) {
+            return Err(ConnError::BadChallenge.into());
         }
-        match timeout(Duration::from_secs(5), conn.next_message()).await? {
-            Message::Handshake(HandshakeMessage::Success) => Ok(conn),
-            _ => return Err(ConnError::HandshakeFailure.into()),
+        conn.encrypt.store(true, Ordering::SeqCst);
+        conn.resume_token = Some(resume_token);
+        Ok(conn)
+    }
+
+    /// Lets a new connection take over this session, as long as it connects within the server's
+    /// grace period
+    pub fn resumption(&self) -> Option<Resumption> {
+        Some(Resumption {
+            token: self.resume_token?,
+        })
+    }
+
+    /// Sends a handshake message unreliably until the server answers it, retransmitting it
+    /// every `HANDSHAKE_RETRY` for up to `HANDSHAKE_TIMEOUT`. Answers to an earlier step that
+    /// arrive late are recognized by `stale` and skipped.
+    async fn request(&self, message: Message, stale: fn(&Message) -> bool) -> Result<Message> {
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        loop {
+            self.send(message.clone())?;
+            self.flush().await?;
+            let retry = (Instant::now() + HANDSHAKE_RETRY).min(deadline);
+            loop {
+                let wait = retry.saturating_duration_since(Instant::now());
+                match timeout(wait, self.recv_message()).await {
+                    Ok(reply) => {
+                        let reply = reply?;
+                        if !stale(&reply) {
+                            return Ok(reply);
+                        }
+                    }
+                    Err(_) if Instant::now() >= deadline => {
+                        return Err(ConnError::HandshakeTimeout.into())
+                    }
+                    Err(_) => break,
+                }
+            }
+            trace!("no answer to {:?}, retransmitting", message.kind());
         }
     }
 
-    pub async fn send(&self, msg: Message) -> Result<()> {
-        let data = Bytes::from(bincode::serialize(&msg)?);
-        let packet = Packet::new(self.client_sequence.load(Ordering::SeqCst), data);
-        let bytes = packet.to_bytes()?;
-        trace!("SEND {:?}\n{}", msg, hexdump(&bytes));
-        self.socket.send_to(&bytes, &self.remote).await?;
-        self.client_sequence.fetch_add(1, Ordering::SeqCst);
+    /// Waits for the server's key proof and resumption token, skipping messages that may
+    /// arrive before them. Our response is sent reliably, so ticking while we wait
+    /// retransmits it if it was lost.
+    async fn recv_success(&self) -> Result<(Proof, ResumeToken)> {
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if wait == Duration::from_secs(0) {
+                return Err(ConnError::HandshakeTimeout.into());
+            }
+            let message = match timeout(wait.min(HANDSHAKE_RETRY), self.recv_message()).await {
+                Ok(message) => message?,
+                Err(_) => {
+                    self.tick().await?;
+                    continue;
+                }
+            };
+            match message {
+                Message::Handshake(HandshakeMessage::Success { proof, resume, .. }) => {
+                    return Ok((proof, resume))
+                }
+                // the server has not seen our response yet
+                Message::Handshake(HandshakeMessage::Challenge { .. }) => self.tick().await?,
+                // late cookies, or gameplay messages overtaking a lost success
+                Message::Cookie(_) | Message::Refresh(_) | Message::Heartbeat => continue,
+                other => return Err(refusal(other).into()),
+            }
+        }
+    }
+
+    /// Queues a message, to be sent on the next flush
+    pub fn send(&self, msg: Message) -> Result<()> {
+        self.send_on(Channel::Unreliable, msg)
+    }
+
+    /// Queues a message that is retransmitted until acknowledged and delivered in order
+    pub fn send_reliable(&self, msg: Message) -> Result<()> {
+        self.send_on(Channel::ReliableOrdered, msg)
+    }
+
+    pub fn send_on(&self, channel: Channel, msg: Message) -> Result<()> {
+        let data = Bytes::from(self.codec.lock().unwrap().encode(&msg)?);
+        trace!("SEND {:?} on {:?}", msg, channel);
+        let channel_sequence =
+            self.channels
+                .lock()
+                .unwrap()
+                .outgoing(channel, &data, Instant::now());
+        self.outbox
+            .lock()
+            .unwrap()
+            .push(channel, channel_sequence, data)?;
         Ok(())
     }
 
-    async fn recv1(&self) -> Result<Packet> {
+    /// Queues reliable messages that were lost or have not been acknowledged in time, discards
+    /// incomplete fragmented messages that have timed out, and flushes the outgoing queue. Once
+    /// connected, a packet is sent even if nothing is queued as long as packets have arrived
+    /// since the last one, as it carries their acks.
+    pub async fn tick(&self) -> Result<()> {
+        let now = Instant::now();
+        let sync_due = {
+            let mut clock = self.clock.lock().unwrap();
+            let due = self.encrypt.load(Ordering::SeqCst) && clock.due(now);
+            if due {
+                clock.on_request(now);
+            }
+            due
+        };
+        if sync_due {
+            self.send(Message::TimeRequest(Utc::now()))?;
+        }
+        let expired = self.reassembler.lock().unwrap().expire(now);
+        if expired > 0 {
+            warn!("discarded {} incomplete fragmented messages", expired);
+        }
+        let due = self.channels.lock().unwrap().due(now);
+        for (channel, channel_sequence, data) in due {
+            trace!("retransmitting {:?} message {}", channel, channel_sequence);
+            self.outbox
+                .lock()
+                .unwrap()
+                .push(channel, channel_sequence, data)?;
+        }
+        let mut packets = self.outbox.lock().unwrap().drain();
+        // without acks the server would take its packets for lost, and its round-trip times
+        // would include the wait for our next message
+        if packets.is_empty()
+            && self.encrypt.load(Ordering::SeqCst)
+            && self.ack_pending.load(Ordering::SeqCst)
+        {
+            packets.push(vec![]);
+        }
+        self.send_packets(packets).await
+    }
+
+    /// Sends every queued message, packing as many of them into each packet as possible
+    pub async fn flush(&self) -> Result<()> {
+        let packets = self.outbox.lock().unwrap().drain();
+        self.send_packets(packets).await
+    }
+
+    /// Sends a packet for each element of `packets`, each carrying the given chunks
+    async fn send_packets(&self, packets: Vec<Vec<Chunk>>) -> Result<()> {
+        for chunks in packets {
+            // fetch_add wraps around on overflow, matching the serial number arithmetic
+            let sequence = SequenceNumber(self.client_sequence.fetch_add(1, Ordering::SeqCst));
+            let ack_header = self.window.lock().unwrap().ack_header();
+            self.ack_pending.store(false, Ordering::SeqCst);
+            let (timestamp, clock_synced) = {
+                let clock = self.clock.lock().unwrap();
+                (clock.server_time(Utc::now()), clock.is_synced())
+            };
+            // the server only checks timestamps once they are on its clock
+            let packet = Packet::new(sequence, ack_header, Chunk::encode(&chunks))
+                .with_connection_id(self.connection_id)
+                .with_timestamp(timestamp)
+                .with_clock_synced(clock_synced);
+            let bytes = {
+                let cipher = self.cipher.lock().unwrap();
+                let cipher = cipher
+                    .as_ref()
+                    .filter(|_| self.encrypt.load(Ordering::SeqCst));
+                packet.encode(self.header_format, cipher)?
+            };
+            trace!("SEND {:?}\n{:?}\n{}", packet, chunks, hexdump(&bytes));
+            self.socket.send_to(&bytes, &self.remote).await?;
+            self.stats
+                .lock()
+                .unwrap()
+                .on_send(sequence, bytes.len(), Instant::now());
+            {
+                let mut channels = self.channels.lock().unwrap();
+                for chunk in &chunks {
+                    channels.on_sent(
+                        chunk.channel,
+                        chunk.channel_sequence,
+                        chunk.fragment,
+                        sequence,
+                    );
+                }
+            }
+            let lost = self.acks.lock().unwrap().on_send(sequence);
+            if let Some(lost) = lost {
+                self.stats.lock().unwrap().on_delivery(lost, Instant::now());
+                self.channels.lock().unwrap().on_delivery(lost);
+                self.notify(lost);
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the server we are leaving. Waits up to `CLOSE_TIMEOUT` for the server to
+    /// acknowledge it, retransmitting it meanwhile, as the server would otherwise keep our
+    /// session around until it idles out.
+    pub async fn close(&self) -> Result<()> {
+        self.send_reliable(Message::Disconnect)?;
+        self.flush().await?;
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while self.channels.lock().unwrap().reliable_tx().unacked() > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("the server did not acknowledge our disconnect");
+                break;
+            }
+            // the ack comes with whatever the server sends next, anything else is ignored
+            let wait = CLOSE_POLL.min(deadline - now);
+            let _ = timeout(wait, self.recv_message()).await;
+            self.tick().await?;
+        }
+        Ok(())
+    }
+
+    /// Stream of delivery notifications for packets sent to the server. Only the most recent
+    /// receiver gets notified.
+    pub fn deliveries(&self) -> mpsc::UnboundedReceiver<Delivery> {
+        let (tx, rx) = mpsc::unbounded();
+        *self.delivery_tx.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    fn notify(&self, delivery: Delivery) {
+        trace!("{:?}", delivery);
+        let mut delivery_tx = self.delivery_tx.lock().unwrap();
+        if let Some(tx) = &*delivery_tx {
+            if tx.unbounded_send(delivery).is_err() {
+                // receiver went away
+                *delivery_tx = None;
+            }
+        }
+    }
+
+    /// Snapshot of the receive window, including reordering and duplicate counts
+    pub fn replay_window(&self) -> ReplayWindow {
+        self.window.lock().unwrap().clone()
+    }
+
+    /// Receives and decodes a packet, returning it along with its size on the wire
+    async fn recv1(&self) -> Result<(Packet, usize)> {
         trace!("recv1");
         let mut buffer = [0u8; 65507];
         let (size, remote) = self.socket.recv_from(&mut buffer).await?;
@@ -75,31 +509,188 @@ impl Conn {
         }
         let datagram = Bytes::from(&buffer[..size]);
         trace!("RECV <bytes>\n{}", hexdump(&datagram));
-        let packet = Packet::from_bytes(datagram)?;
-        Ok(packet)
+        let cipher = self.cipher.lock().unwrap();
+        let packet = match Packet::decode(datagram.clone(), cipher.as_ref()) {
+            // until the server has proven its keys it may still refuse us in plaintext, e.g.
+            // because our credentials are not valid
+            Err(PacketError::AuthenticationFailed) if !self.encrypt.load(Ordering::SeqCst) => {
+                Packet::from_bytes(datagram)?
+            }
+            result => result?,
+        };
+        Ok((packet, size))
+    }
+
+    /// Latency, loss and throughput of the connection so far
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.lock().unwrap().snapshot()
+    }
+
+    /// Estimate of the server's clock right now, to be used instead of our own clock for
+    /// anything the server needs to make sense of
+    pub fn server_time(&self) -> DateTime<Utc> {
+        self.clock.lock().unwrap().server_time(Utc::now())
     }
 
-    pub async fn next_message(&self) -> Message {
+    /// Waits for the next message from the server, or for the connection to be lost
+    pub async fn next_event(&self) -> Event {
         loop {
-            let server_sequence = self.server_sequence.clone();
-            if let Ok(packet) = self.recv1().await {
-                if packet.sequence_number > server_sequence.load(Ordering::SeqCst) {
-                    match bincode::deserialize::<Message>(&packet.message) {
-                        Ok(message) => {
-                            self.server_sequence
-                                .store(packet.sequence_number, Ordering::SeqCst);
-                            debug!("RECV {:?}", message);
-                            return message;
-                        }
-                        Err(err) => {
-                            warn!("error decoding message: {}", err);
+            let deadline = *self.last_heard.lock().unwrap() + SERVER_TIMEOUT;
+            let now = Instant::now();
+            if now >= deadline {
+                return Event::Disconnected {
+                    reason: DisconnectReason::Timeout,
+                };
+            }
+            match timeout(deadline - now, self.recv_message()).await {
+                Ok(Ok(Message::Disconnect)) => {
+                    return Event::Disconnected {
+                        reason: DisconnectReason::Closed,
+                    }
+                }
+                Ok(Ok(message)) => return Event::Message(message),
+                // reading failed and we try again, or we ran out of time and check the deadline
+                Ok(Err(_)) | Err(_) => continue,
+            }
+        }
+    }
+
+    /// Like `next_event`, but fails when reading from the socket fails. Undecodable packets are
+    /// skipped, except for the server rejecting our protocol version, which results in
+    /// `ConnError::VersionMismatch`.
+    async fn recv_message(&self) -> Result<Message> {
+        loop {
+            if let Some(message) = self.inbox.lock().unwrap().pop_front() {
+                return Ok(message);
+            }
+            match self.recv1().await {
+                Ok((packet, size)) => self.on_packet(packet, size),
+                Err(err) => {
+                    match err.downcast_ref() {
+                        Some(PacketError::UnsupportedVersion { version }) => {
+                            return Err(ConnError::VersionMismatch {
+                                server: *version,
+                                client: PROTOCOL_VERSION,
+                            }
+                            .into());
                         }
+                        // e.g. a retransmitted challenge that is no longer encrypted the way
+                        // we expect, which is harmless
+                        Some(err) => warn!("dropping packet: {}", err),
+                        None => return Err(err),
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_packet(&self, packet: Packet, size: usize) {
+        // the server stamps packets with its own clock, which we can only check against once
+        // we know how far off ours is
+        let acceptance = {
+            let clock = self.clock.lock().unwrap();
+            let mut window = self.window.lock().unwrap();
+            if clock.is_synced() {
+                let now = clock.server_time(Utc::now());
+                match window.accept_packet(&packet, &self.timestamp_bounds, now) {
+                    Ok(acceptance) => acceptance,
+                    Err(err) => {
+                        warn!("dropping packet {}: {}", packet.sequence_number, err);
+                        return;
+                    }
+                }
+            } else {
+                window.accept(packet.sequence_number)
+            }
+        };
+        if !acceptance.is_accepted() {
+            warn!(
+                "{:?} packet {}, ignoring",
+                acceptance, packet.sequence_number
+            );
+            return;
+        }
+        *self.last_heard.lock().unwrap() = Instant::now();
+        self.ack_pending.store(true, Ordering::SeqCst);
+        self.stats
+            .lock()
+            .unwrap()
+            .on_receive(packet.timestamp, size, Utc::now());
+        let deliveries = self
+            .acks
+            .lock()
+            .unwrap()
+            .on_ack(packet.ack, packet.ack_bits);
+        let now = Instant::now();
+        for delivery in deliveries {
+            self.stats.lock().unwrap().on_delivery(delivery, now);
+            self.channels.lock().unwrap().on_delivery(delivery);
+            self.notify(delivery);
+        }
+        let server_sent = packet.timestamp;
+        let chunks = match Chunk::decode(packet.message) {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                warn!("dropping packet with malformed payload: {}", err);
+                return;
+            }
+        };
+        for chunk in chunks {
+            let reassembled = self.reassembler.lock().unwrap().reassemble(chunk, now);
+            let chunk = match reassembled {
+                Ok(Some(chunk)) => chunk,
+                // waiting for the remaining fragments
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("dropping fragmented message: {}", err);
+                    continue;
+                }
+            };
+            let payloads = self.channels.lock().unwrap().incoming(
+                chunk.channel,
+                chunk.channel_sequence,
+                chunk.payload,
+            );
+            let mut inbox = self.inbox.lock().unwrap();
+            for payload in payloads {
+                let message = self
+                    .codec
+                    .lock()
+                    .unwrap()
+                    .decode_limited(&payload, &self.decode_limits);
+                // the server encodes everything after its success with the codec it picked
+                if let Ok(Message::Handshake(HandshakeMessage::Success { codec, .. })) = &message {
+                    debug!("using the {} codec", codec);
+                    *self.codec.lock().unwrap() = *codec;
+                }
+                match message {
+                    Ok(Message::TimeResponse {
+                        client_sent,
+                        server_received,
+                    }) => {
+                        debug!("RECV time response");
+                        self.clock.lock().unwrap().on_response(
+                            client_sent,
+                            server_received,
+                            server_sent,
+                            Utc::now(),
+                        );
+                    }
+                    // proves we are at the address this came to, the game never sees it
+                    Ok(Message::PathChallenge(data)) => {
+                        debug!("RECV path challenge");
+                        self.send(Message::PathResponse(data))
+                            .unwrap_or_else(|err| warn!("error answering path challenge: {}", err));
+                    }
+                    Ok(message) => {
+                        debug!("RECV {:?}", message);
+                        inbox.push_back(message);
+                    }
+                    Err(err) => {
+                        warn!("error decoding message: {}", err);
                     }
-                } else {
-                    warn!("received packet with stale sequence number");
                 }
             }
-            // otherwise reading failed and we try again
         }
     }
 }
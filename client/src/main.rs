@@ -1,6 +1,7 @@
 #![feature(mem_take)]
 #![feature(async_closure)]
 
+use std::env;
 use std::net::SocketAddr;
 
 use std::str::FromStr;
@@ -9,7 +10,9 @@ use std::time::Duration;
 
 use async_std::task;
 
-use async_std::sync::Arc;
+use async_std::sync::{Arc, Weak};
+
+use chrono::{DateTime, Utc};
 
 use futures::channel::mpsc;
 use futures::executor;
@@ -31,28 +34,136 @@ use ggez::input::keyboard::KeyCode;
 use ggez::nalgebra as na;
 use log::{info, trace, warn};
 
-use conn::Conn;
-use shared::future::retry;
-use shared::{handshake::*, hexdump, logging, packet::Packet, proto::*, Result};
+use conn::{Conn, ConnError, Event, Resumption};
+use shared::codec::CodecKind;
+use shared::credentials::Credentials;
+use shared::future::{retry_unless, Backoff};
+use shared::packet::{HeaderFormat, Packet};
+use shared::stats::StatsSnapshot;
+use shared::{handshake::*, hexdump, logging, proto::*, Result};
 
 mod conn;
 
-async fn keep_alive(conn: Arc<Conn>) {
+/// Number of attempts at connecting before telling the player the connection is lost. We keep
+/// trying in the background after that.
+const CONNECT_ATTEMPTS: usize = 5;
+
+/// Delays between attempts at connecting
+const CONNECT_BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(250),
+    max: Duration::from_secs(10),
+};
+
+/// Sends heartbeats until the connection is dropped
+async fn keep_alive(conn: Weak<Conn>) {
     loop {
         Delay::new(Duration::from_secs(5)).await;
-        conn.send(Message::Heartbeat)
-            .await
-            .unwrap_or_else(|err| warn!("error sending heartbeat: {}", err));
+        match conn.upgrade() {
+            Some(conn) => conn
+                .send(Message::Heartbeat)
+                .unwrap_or_else(|err| warn!("error sending heartbeat: {}", err)),
+            None => break,
+        }
+    }
+}
+
+/// What we need to connect to the server, again and again
+#[derive(Debug, Clone)]
+struct Settings {
+    remote: SocketAddr,
+    header_format: HeaderFormat,
+    codec: CodecKind,
+    credentials: Credentials,
+}
+
+/// Reason the server refused us for good, if that is why connecting failed
+fn permanent_refusal(err: &(dyn std::error::Error + Send + Sync + 'static)) -> Option<ConnError> {
+    err.downcast_ref::<ConnError>()
+        .copied()
+        .filter(|err| err.is_permanent())
+}
+
+/// Connects in the background, picking up the previous session if `resume` is given, and
+/// reports the outcome on `tx`. Every `CONNECT_ATTEMPTS` failed attempts are reported as well,
+/// after which it keeps trying until it succeeds, the server refuses us for good or the game
+/// quits.
+async fn connect(
+    settings: Settings,
+    resume: Option<Resumption>,
+    tx: mpsc::UnboundedSender<Result<Conn>>,
+) {
+    loop {
+        let result = retry_unless(
+            CONNECT_ATTEMPTS,
+            CONNECT_BACKOFF,
+            |err| permanent_refusal(err).is_some(),
+            || {
+                Conn::connect(
+                    settings.remote,
+                    settings.header_format,
+                    settings.codec,
+                    settings.credentials.clone(),
+                    resume.clone(),
+                )
+            },
+        )
+        .await;
+        let done = match &result {
+            Ok(_) => true,
+            Err(err) => permanent_refusal(err.as_ref()).is_some(),
+        };
+        // the receiver is only gone once the game has quit
+        if tx.unbounded_send(result).is_err() || done {
+            break;
+        }
+        Delay::new(CONNECT_BACKOFF.max).await;
+    }
+}
+
+async fn next_event(conn: Arc<Conn>) -> Event {
+    conn.next_event().await
+}
+
+/// State of the connection as shown to the player
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Connecting,
+    Connected,
+    Reconnecting,
+    Lost,
+    /// The server refused us for a reason that retrying does not fix
+    Refused(ConnError),
+}
+
+impl Status {
+    fn label(self) -> Option<String> {
+        match self {
+            Status::Connecting => Some("connecting...".to_owned()),
+            Status::Connected => None,
+            Status::Reconnecting => Some("reconnecting...".to_owned()),
+            Status::Lost => Some("lost connection, retrying...".to_owned()),
+            Status::Refused(err) => Some(format!("connection refused: {}", err)),
+        }
     }
 }
 
 struct GameState {
     positions: Vec<(f32, f32)>,
+    status: Status,
+    /// Connection statistics shown while connected
+    stats: Option<StatsSnapshot>,
+    /// Estimate of the server's clock when the statistics were taken
+    server_time: Option<DateTime<Utc>>,
 }
 
 impl GameState {
     fn new() -> ggez::GameResult<GameState> {
-        Ok(GameState { positions: vec![] })
+        Ok(GameState {
+            positions: vec![],
+            status: Status::Connecting,
+            stats: None,
+            server_time: None,
+        })
     }
 }
 
@@ -71,6 +182,23 @@ fn draw(state: &GameState, ctx: &mut ggez::Context) -> ggez::GameResult {
         graphics::draw(ctx, &circle, (na::Point2::new(50.0, 50.0),))?;
     }
 
+    let hud = match (state.status.label(), &state.stats) {
+        (Some(label), _) => Some(label),
+        (None, Some(stats)) => match state.server_time {
+            Some(time) => Some(format!(
+                "{}\nserver time {}",
+                stats,
+                time.format("%H:%M:%S%.3f")
+            )),
+            None => Some(stats.to_string()),
+        },
+        (None, None) => None,
+    };
+    if let Some(hud) = hud {
+        let text = graphics::Text::new(hud);
+        graphics::draw(ctx, &text, (na::Point2::new(10.0, 10.0), graphics::BLACK))?;
+    }
+
     graphics::present(ctx)?;
     Ok(())
 }
@@ -97,22 +225,34 @@ fn on_event(event: &Event, ctx: &mut ggez::Context) {
     }
 }
 
+/// Reads a ticket from `BASEPLATE_TICKET`, or else a username and token from
+/// `BASEPLATE_USERNAME` and `BASEPLATE_TOKEN`
+fn credentials_from_env() -> Credentials {
+    match env::var("BASEPLATE_TICKET") {
+        Ok(ticket) => Credentials::Ticket(ticket.parse().unwrap()),
+        Err(_) => Credentials::Token {
+            username: env::var("BASEPLATE_USERNAME").unwrap_or_else(|_| "player".to_owned()),
+            token: env::var("BASEPLATE_TOKEN").unwrap_or_default(),
+        },
+    }
+}
+
 async fn async_main() -> () {
     logging::setup().unwrap();
 
     trace!("client starting");
 
-    // Try to create a connection, retrying 5 times
-    let conn = Arc::new(
-        retry(5, async || {
-            Conn::connect(SocketAddr::from_str("127.0.0.1:12345")?).await
-        })
-        .await
-        .expect("unable to connect to the server"),
-    );
-    info!("connection estabilished");
+    let settings = Settings {
+        remote: SocketAddr::from_str("127.0.0.1:12345").unwrap(),
+        header_format: HeaderFormat::from_env().unwrap(),
+        codec: CodecKind::from_env().unwrap(),
+        credentials: credentials_from_env(),
+    };
 
-    task::spawn(keep_alive(conn.clone()));
+    // Connect in the background, so that the window can show how it is going
+    let (conn_tx, mut conn_rx) = mpsc::unbounded();
+    task::spawn(connect(settings.clone(), None, conn_tx.clone()));
+    let mut conn: Option<Arc<Conn>> = None;
 
     let cb = ggez::ContextBuilder::new("baseplate", "strax").window_setup(WindowSetup {
         title: "baseplate".to_owned(),
@@ -126,11 +266,11 @@ async fn async_main() -> () {
 
     let (tx, mut rx) = mpsc::unbounded();
 
-    let next_message = Fuse::terminated();
+    let next = Fuse::terminated();
     let input_tick = Fuse::terminated();
-    pin_mut!(next_message, input_tick);
-    next_message.set(conn.next_message().fuse());
+    pin_mut!(next, input_tick);
     input_tick.set(Delay::new(Duration::from_millis(16)).fuse());
+    draw(state, ctx).unwrap();
 
     while ctx.continuing {
         ctx.timer_context.tick();
@@ -142,37 +282,78 @@ async fn async_main() -> () {
                 on_event(&event, ctx);
             },
             () = input_tick => {
-                handle_movement(ctx, &conn).await;
+                if let Some(conn) = &conn {
+                    handle_movement(ctx, conn).await;
+                    conn.tick()
+                        .await
+                        .unwrap_or_else(|err| warn!("error flushing connection: {}", err));
+                }
                 input_tick.set(Delay::new(Duration::from_millis(16)).fuse());
             }
-            msg = next_message => {
-                match msg {
-                    Message::Refresh(positions) => {
+            result = conn_rx.select_next_some() => {
+                match result {
+                    Ok(established) => {
+                        info!("connection established");
+                        let established = Arc::new(established);
+                        task::spawn(keep_alive(Arc::downgrade(&established)));
+                        next.set(next_event(established.clone()).fuse());
+                        conn = Some(established);
+                        state.status = Status::Connected;
+                    }
+                    Err(err) => {
+                        warn!("unable to connect to the server: {}", err);
+                        state.status = match permanent_refusal(err.as_ref()) {
+                            Some(err) => Status::Refused(err),
+                            None => Status::Lost,
+                        };
+                    }
+                }
+                draw(state, ctx).unwrap();
+            }
+            event = next => {
+                match event {
+                    Event::Message(Message::Refresh(positions)) => {
                         state.positions = positions;
+                        state.stats = conn.as_ref().map(|conn| conn.stats());
+                        state.server_time = conn.as_ref().map(|conn| conn.server_time());
                         draw(state, ctx).unwrap();
                     },
-                    _ => {}
+                    Event::Message(_) => {}
+                    Event::Disconnected { reason } => {
+                        warn!("disconnected from the server: {}", reason);
+                        let resume = conn.take().and_then(|conn| conn.resumption());
+                        task::spawn(connect(settings.clone(), resume, conn_tx.clone()));
+                        state.status = Status::Reconnecting;
+                        draw(state, ctx).unwrap();
+                        continue;
+                    }
+                }
+                if let Some(conn) = &conn {
+                    next.set(next_event(conn.clone()).fuse());
                 }
-                next_message.set(conn.next_message().fuse());
             }
         }
     }
 
-    conn.send(Message::Disconnect).await;
+    if let Some(conn) = conn {
+        conn.close()
+            .await
+            .unwrap_or_else(|err| warn!("error disconnecting: {}", err));
+    }
 }
 
 async fn handle_movement(ctx: &mut ggez::Context, conn: &Conn) {
     if input::keyboard::is_key_pressed(ctx, KeyCode::Right) {
-        conn.send(Message::Move { dx: 1.0, dy: 0.0 }).await;
+        conn.send(Message::Move { dx: 1.0, dy: 0.0 });
     }
     if input::keyboard::is_key_pressed(ctx, KeyCode::Left) {
-        conn.send(Message::Move { dx: -1.0, dy: 0.0 }).await;
+        conn.send(Message::Move { dx: -1.0, dy: 0.0 });
     }
     if input::keyboard::is_key_pressed(ctx, KeyCode::Down) {
-        conn.send(Message::Move { dx: 0.0, dy: 1.0 }).await;
+        conn.send(Message::Move { dx: 0.0, dy: 1.0 });
     }
     if input::keyboard::is_key_pressed(ctx, KeyCode::Up) {
-        conn.send(Message::Move { dx: 0.0, dy: -1.0 }).await;
+        conn.send(Message::Move { dx: 0.0, dy: -1.0 });
     }
 }
 
@@ -0,0 +1,239 @@
+use log::warn;
+use shared::credentials::{self, Credentials};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+
+/// Authenticated player, as established during the handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub username: String,
+}
+
+/// Checks the credentials a client presents during the handshake
+pub trait Authenticator: fmt::Debug + Send + Sync {
+    /// Returns the identity the credentials prove, or `None` if they are not valid. Credentials
+    /// bound to a handshake are only valid for the one with `transcript`.
+    fn authenticate(&self, credentials: &Credentials, transcript: &[u8]) -> Option<Identity>;
+}
+
+/// Usernames and tokens read from a file with one `username:token` pair per line. Empty
+/// lines and lines starting with `#` are ignored.
+///
+/// Clients prove that they hold a token with a `Credentials::TokenProof` rather than sending
+/// it, so a token sent as is gets rejected.
+pub struct StaticFile {
+    tokens: HashMap<String, String>,
+}
+
+impl fmt::Debug for StaticFile {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "StaticFile {{ {} users }}", self.tokens.len())
+    }
+}
+
+impl StaticFile {
+    pub fn load(path: &Path) -> io::Result<StaticFile> {
+        Ok(StaticFile::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn parse(contents: &str) -> StaticFile {
+        let tokens = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(username), Some(token)) => Some((username.to_owned(), token.to_owned())),
+                    _ => {
+                        warn!("ignoring malformed line in users file");
+                        None
+                    }
+                }
+            })
+            .collect();
+        StaticFile { tokens }
+    }
+}
+
+impl Authenticator for StaticFile {
+    fn authenticate(&self, credentials: &Credentials, transcript: &[u8]) -> Option<Identity> {
+        match credentials {
+            Credentials::TokenProof { username, proof } => {
+                let token = self.tokens.get(username)?;
+                if credentials::verify_token_proof(token, username, transcript, proof) {
+                    Some(Identity {
+                        username: username.clone(),
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Tickets signed with a secret shared with the login service, see `Ticket`
+pub struct SignedTicket {
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for SignedTicket {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("SignedTicket { .. }")
+    }
+}
+
+impl SignedTicket {
+    pub fn new(secret: &[u8]) -> SignedTicket {
+        SignedTicket {
+            secret: secret.to_owned(),
+        }
+    }
+}
+
+impl Authenticator for SignedTicket {
+    fn authenticate(&self, credentials: &Credentials, _transcript: &[u8]) -> Option<Identity> {
+        match credentials {
+            Credentials::Ticket(ticket) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                if ticket.verify(&self.secret, now) {
+                    Some(Identity {
+                        username: ticket.username.clone(),
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Accepts whatever username the client claims. Only used when no other authenticator is
+/// configured, which is handy for local testing.
+#[derive(Debug)]
+pub struct Anonymous;
+
+impl Authenticator for Anonymous {
+    fn authenticate(&self, credentials: &Credentials, _transcript: &[u8]) -> Option<Identity> {
+        Some(Identity {
+            username: credentials.username().to_owned(),
+        })
+    }
+}
+
+/// Accepts credentials accepted by any of the authenticators
+#[derive(Debug)]
+pub struct Any(pub Vec<Box<dyn Authenticator>>);
+
+impl Authenticator for Any {
+    fn authenticate(&self, credentials: &Credentials, transcript: &[u8]) -> Option<Identity> {
+        self.0
+            .iter()
+            .filter_map(|authenticator| authenticator.authenticate(credentials, transcript))
+            .next()
+    }
+}
+
+/// Builds the authenticators enabled in the config
+pub fn from_config(config: &Config) -> io::Result<Box<dyn Authenticator>> {
+    let mut authenticators: Vec<Box<dyn Authenticator>> = vec![];
+    if let Some(path) = &config.users_file {
+        authenticators.push(Box::new(StaticFile::load(path)?));
+    }
+    if let Some(secret) = &config.ticket_secret {
+        authenticators.push(Box::new(SignedTicket::new(secret.0.as_bytes())));
+    }
+    if authenticators.is_empty() {
+        warn!("no authentication configured, anyone can join");
+        return Ok(Box::new(Anonymous));
+    }
+    Ok(Box::new(Any(authenticators)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::credentials::Ticket;
+
+    const TRANSCRIPT: &[u8] = b"transcript";
+
+    fn token(username: &str, token: &str) -> Credentials {
+        Credentials::Token {
+            username: username.to_owned(),
+            token: token.to_owned(),
+        }
+        .bind(TRANSCRIPT)
+    }
+
+    #[test]
+    fn test_static_file() {
+        let users = StaticFile::parse("# users\nalice:secret\n\nbob:hunter2\nmalformed\n");
+        assert_eq!(
+            users.authenticate(&token("alice", "secret"), TRANSCRIPT),
+            Some(Identity {
+                username: "alice".to_owned()
+            })
+        );
+        assert_eq!(
+            users.authenticate(&token("alice", "hunter2"), TRANSCRIPT),
+            None
+        );
+        assert_eq!(
+            users.authenticate(&token("carol", "secret"), TRANSCRIPT),
+            None
+        );
+        // a proof from another handshake, or the token itself
+        assert_eq!(
+            users.authenticate(&token("alice", "secret"), b"other"),
+            None
+        );
+        let plain = Credentials::Token {
+            username: "alice".to_owned(),
+            token: "secret".to_owned(),
+        };
+        assert_eq!(users.authenticate(&plain, TRANSCRIPT), None);
+    }
+
+    #[test]
+    fn test_signed_ticket() {
+        let tickets = SignedTicket::new(b"shared secret");
+        let valid = Ticket::sign(b"shared secret", "alice", u64::MAX);
+        let expired = Ticket::sign(b"shared secret", "alice", 1);
+        let forged = Ticket::sign(b"guessed secret", "alice", u64::MAX);
+        assert!(tickets
+            .authenticate(&Credentials::Ticket(valid.clone()), TRANSCRIPT)
+            .is_some());
+        assert!(tickets
+            .authenticate(&Credentials::Ticket(expired), TRANSCRIPT)
+            .is_none());
+        assert!(tickets
+            .authenticate(&Credentials::Ticket(forged), TRANSCRIPT)
+            .is_none());
+        assert!(tickets
+            .authenticate(&token("alice", "secret"), TRANSCRIPT)
+            .is_none());
+
+        let any = Any(vec![
+            Box::new(StaticFile::parse("bob:hunter2")),
+            Box::new(tickets),
+        ]);
+        assert!(any
+            .authenticate(&token("bob", "hunter2"), TRANSCRIPT)
+            .is_some());
+        assert!(any
+            .authenticate(&Credentials::Ticket(valid), TRANSCRIPT)
+            .is_some());
+    }
+}
@@ -0,0 +1,218 @@
+use shared::codec::{CodecKind, DecodeLimits};
+use shared::packet::{HeaderFormat, TimestampBounds};
+use shared::proto::MessageKind;
+use std::env;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Server settings, read from `BASEPLATE_*` environment variables
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Header format used for packets sent to clients
+    pub header_format: HeaderFormat,
+    /// Codecs clients may pick for their messages. Clients proposing another one get `Bincode`,
+    /// which every client supports.
+    pub codecs: Vec<CodecKind>,
+    /// Size limits for messages from clients. `BASEPLATE_MESSAGE_LIMIT` sets the limit for
+    /// most types, `BASEPLATE_MESSAGE_LIMITS` those of particular types as in
+    /// `Refresh=65536,Handshake=4096`.
+    pub decode_limits: DecodeLimits,
+    /// Number of malformed messages after which a client is dropped, if any
+    pub max_malformed: Option<u64>,
+    /// Time a client has to complete the handshake before its session is dropped
+    pub handshake_timeout: Duration,
+    /// Time without hearing from a connected client after which its session is evicted. Clients
+    /// send a heartbeat every 5 seconds.
+    pub idle_timeout: Duration,
+    /// Time after last hearing from a client during which it may resume its session
+    pub resume_grace: Duration,
+    /// Time without hearing from a connected client after which a plaintext `Connect` from its
+    /// address is taken as the client starting over, having lost its keys. Until then it may be
+    /// a late or replayed one, as the client would still be sending heartbeats.
+    pub restart_after: Duration,
+    /// How far the timestamps of packets from connected clients may be from our clock. Clients
+    /// stamp their packets with their estimate of our clock.
+    pub timestamp_bounds: TimestampBounds,
+    /// Maximum number of sessions that are connected or negotiating
+    pub max_sessions: usize,
+    /// Addresses refused during the handshake
+    pub banned: Vec<IpAddr>,
+    /// File with `username:token` pairs, see `auth::StaticFile`
+    pub users_file: Option<PathBuf>,
+    /// Secret shared with the login service signing tickets, see `auth::SignedTicket`
+    pub ticket_secret: Option<Secret>,
+}
+
+/// String that is left out of debug output
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("<secret>")
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            header_format: HeaderFormat::default(),
+            codecs: CodecKind::ALL.to_vec(),
+            decode_limits: DecodeLimits::default(),
+            max_malformed: None,
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(15),
+            resume_grace: Duration::from_secs(30),
+            restart_after: Duration::from_secs(6),
+            timestamp_bounds: TimestampBounds::default(),
+            max_sessions: 32,
+            banned: vec![],
+            users_file: None,
+            ticket_secret: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, String> {
+        let default = Config::default();
+        Ok(Config {
+            header_format: HeaderFormat::from_env()?,
+            codecs: match list("BASEPLATE_CODECS")? {
+                codecs if codecs.is_empty() => default.codecs,
+                codecs => codecs,
+            },
+            decode_limits: message_limits(
+                "BASEPLATE_MESSAGE_LIMITS",
+                match optional("BASEPLATE_MESSAGE_LIMIT")? {
+                    Some(max) => default.decode_limits.with_default(max),
+                    None => default.decode_limits,
+                },
+            )?,
+            max_malformed: optional("BASEPLATE_MAX_MALFORMED")?,
+            handshake_timeout: Duration::from_millis(var(
+                "BASEPLATE_HANDSHAKE_TIMEOUT_MS",
+                default.handshake_timeout.as_millis() as u64,
+            )?),
+            idle_timeout: Duration::from_millis(var(
+                "BASEPLATE_IDLE_TIMEOUT_MS",
+                default.idle_timeout.as_millis() as u64,
+            )?),
+            resume_grace: Duration::from_millis(var(
+                "BASEPLATE_RESUME_GRACE_MS",
+                default.resume_grace.as_millis() as u64,
+            )?),
+            restart_after: Duration::from_millis(var(
+                "BASEPLATE_RESTART_AFTER_MS",
+                default.restart_after.as_millis() as u64,
+            )?),
+            timestamp_bounds: TimestampBounds {
+                max_future_skew: Duration::from_millis(var(
+                    "BASEPLATE_MAX_CLOCK_SKEW_MS",
+                    default.timestamp_bounds.max_future_skew.as_millis() as u64,
+                )?),
+                max_age: Duration::from_millis(var(
+                    "BASEPLATE_MAX_PACKET_AGE_MS",
+                    default.timestamp_bounds.max_age.as_millis() as u64,
+                )?),
+            },
+            max_sessions: var("BASEPLATE_MAX_SESSIONS", default.max_sessions)?,
+            banned: list("BASEPLATE_BANNED")?,
+            users_file: env::var_os("BASEPLATE_USERS_FILE").map(PathBuf::from),
+            ticket_secret: env::var("BASEPLATE_TICKET_SECRET").ok().map(Secret),
+        })
+    }
+}
+
+fn var<T: FromStr>(name: &str, default: T) -> Result<T, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("invalid value {:?} for {}", value, name)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Parses a variable without a default, which is `None` when unset
+fn optional<T: FromStr>(name: &str) -> Result<Option<T>, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid value {:?} for {}", value, name)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parses a comma-separated list, which is empty when the variable is unset
+fn list<T: FromStr>(name: &str) -> Result<Vec<T>, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                item.parse()
+                    .map_err(|_| format!("invalid value {:?} in {}", item, name))
+            })
+            .collect(),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+/// Overrides the limits of particular message types with a comma-separated list of
+/// `Type=bytes` pairs, if the variable is set
+fn message_limits(name: &str, mut limits: DecodeLimits) -> Result<DecodeLimits, String> {
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(_) => return Ok(limits),
+    };
+    for item in value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        let invalid = || format!("invalid value {:?} in {}", item, name);
+        let mut parts = item.splitn(2, '=').map(str::trim);
+        let kind: MessageKind = parts
+            .next()
+            .and_then(|kind| kind.parse().ok())
+            .ok_or_else(invalid)?;
+        let max = parts
+            .next()
+            .and_then(|max| max.parse().ok())
+            .ok_or_else(invalid)?;
+        limits = limits.with_limit(kind, max);
+    }
+    Ok(limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_limits() {
+        let name = "BASEPLATE_TEST_MESSAGE_LIMITS";
+        env::set_var(name, "Refresh=4096, Move = 16,");
+        let limits = message_limits(name, DecodeLimits::new(512)).unwrap();
+        assert_eq!(limits.limit(MessageKind::Refresh), 4096);
+        assert_eq!(limits.limit(MessageKind::Move), 16);
+        assert_eq!(limits.limit(MessageKind::Heartbeat), 512);
+
+        for invalid in &["Refresh", "Refresh=big", "Teleport=16"] {
+            env::set_var(name, invalid);
+            assert!(message_limits(name, DecodeLimits::new(512)).is_err());
+        }
+        env::remove_var(name);
+        assert_eq!(
+            message_limits(name, DecodeLimits::new(512))
+                .unwrap()
+                .limit(MessageKind::Refresh),
+            512
+        );
+    }
+}
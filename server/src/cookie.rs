@@ -0,0 +1,81 @@
+use hmac::{Hmac, Mac, NewMac};
+use rand::random;
+use sha2::Sha256;
+use shared::handshake::Cookie;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Length of the time buckets cookies are bound to. A cookie is accepted during its own bucket
+/// and the one after it, so it stays valid for at least this long.
+pub const COOKIE_LIFETIME: Duration = Duration::from_secs(10);
+
+/// Issues and checks the cookies a client has to echo before the server allocates a session
+/// for it.
+///
+/// Cookies are an HMAC of the client address and the current time bucket under a secret
+/// generated at startup, so checking them needs no per-client state. A spoofed source address
+/// never receives its cookie and thus cannot make the server allocate anything.
+pub struct CookieJar {
+    secret: [u8; 32],
+}
+
+impl fmt::Debug for CookieJar {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("CookieJar { .. }")
+    }
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar { secret: random() }
+    }
+
+    pub fn issue(&self, remote: SocketAddr, now: SystemTime) -> Cookie {
+        let mut cookie = Cookie::default();
+        cookie.copy_from_slice(&self.mac(remote, bucket(now)).finalize().into_bytes());
+        cookie
+    }
+
+    pub fn verify(&self, remote: SocketAddr, cookie: &Cookie, now: SystemTime) -> bool {
+        let bucket = bucket(now);
+        [bucket, bucket.saturating_sub(1)]
+            .iter()
+            .any(|&bucket| self.mac(remote, bucket).verify(cookie).is_ok())
+    }
+
+    fn mac(&self, remote: SocketAddr, bucket: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.secret).expect("HMAC accepts any key");
+        match remote.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&remote.port().to_be_bytes());
+        mac.update(&bucket.to_be_bytes());
+        mac
+    }
+}
+
+fn bucket(now: SystemTime) -> u64 {
+    let elapsed = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_secs() / COOKIE_LIFETIME.as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie() {
+        let jar = CookieJar::new();
+        let remote = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let now = SystemTime::now();
+        let cookie = jar.issue(remote, now);
+
+        assert!(jar.verify(remote, &cookie, now));
+        assert!(jar.verify(remote, &cookie, now + COOKIE_LIFETIME));
+        assert!(!jar.verify(remote, &cookie, now + COOKIE_LIFETIME * 2));
+        assert!(!jar.verify(SocketAddr::from(([127, 0, 0, 1], 4001)), &cookie, now));
+        assert!(!CookieJar::new().verify(remote, &cookie, now));
+    }
+}
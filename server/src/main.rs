@@ -1,26 +1,40 @@
+mod auth;
+mod config;
+mod cookie;
 mod session;
 
 use async_std::net::UdpSocket;
 use std::net::SocketAddr;
 
-use log::{info, trace, warn};
+use log::{debug, info, trace, warn};
+use rand::random;
 use std::collections::HashMap;
 
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
+use auth::{Authenticator, Identity};
 use bytes::Bytes;
+use config::Config;
+use cookie::CookieJar;
 use session::*;
 
-use shared::{hexdump, packet::Packet, proto};
+use shared::codec::{Bincode, Codec, DecodeLimits};
+use shared::handshake::{Cookie, ResumeToken};
+use shared::packet::{
+    self, Chunk, ConnectionId, Fragment, HeaderFormat, Packet, PacketError, SequenceNumber,
+};
+use shared::{channel::Channel, hexdump, proto};
 
 use std::str::FromStr;
 
 use async_std::sync::Arc;
 use async_std::task;
+use futures::channel::mpsc;
 use futures::executor;
 use futures::future;
 use futures::lock::Mutex;
-use shared::proto::Message;
+use futures::stream::StreamExt;
+use shared::proto::{Message, MessageKind};
 
 #[derive(Debug)]
 pub enum SessionMessage {
@@ -29,20 +43,169 @@ pub enum SessionMessage {
     Stop,
 }
 
+/// Something that happened to a session which game logic may want to react to, see
+/// `State::events`
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameEvent {
+    /// A session ended. Its client may still resume it within `Config::resume_grace`.
+    Disconnected {
+        connection_id: ConnectionId,
+        identity: Option<Identity>,
+        reason: DisconnectReason,
+    },
+}
+
 #[derive(Debug)]
 struct State {
-    sessions: HashMap<SocketAddr, Session>,
+    sessions: HashMap<ConnectionId, Session>,
+    /// Connection of the session at each address, for packets from clients that have not
+    /// learnt their connection ID yet
+    addresses: HashMap<SocketAddr, ConnectionId>,
+    /// Sessions that timed out or were replaced by a new connection from the same address,
+    /// kept until they are resumed or `Config::resume_grace` runs out
+    detached: Vec<Session>,
+    config: Arc<Config>,
+    occupancy: Occupancy,
+    authenticator: Arc<dyn Authenticator>,
+    cookies: CookieJar,
+    events_tx: Option<mpsc::UnboundedSender<GameEvent>>,
 }
 
 impl State {
-    fn new() -> Self {
+    fn new(config: Config, authenticator: Arc<dyn Authenticator>) -> Self {
         State {
             sessions: HashMap::new(),
+            addresses: HashMap::new(),
+            detached: vec![],
+            config: Arc::new(config),
+            occupancy: Occupancy::default(),
+            authenticator,
+            cookies: CookieJar::new(),
+            events_tx: None,
+        }
+    }
+
+    /// Stream of events for game logic. Only the most recent receiver gets notified.
+    fn events(&mut self) -> mpsc::UnboundedReceiver<GameEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.events_tx = Some(tx);
+        rx
+    }
+
+    fn notify(&mut self, event: GameEvent) {
+        if let Some(tx) = &self.events_tx {
+            if tx.unbounded_send(event).is_err() {
+                self.events_tx = None;
+            }
         }
     }
 
-    async fn broadcast(&mut self, msg: Message) {
-        future::join_all(self.sessions.values_mut().map(|s| s.send(&msg))).await;
+    /// Picks an unused connection ID for a new session
+    fn connection_id(&self) -> ConnectionId {
+        loop {
+            let id = ConnectionId(random());
+            if !id.is_none() && !self.sessions.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Session a packet belongs to, going by its connection ID or else its source address
+    fn lookup(&self, id: Option<ConnectionId>, remote: SocketAddr) -> Option<ConnectionId> {
+        match id {
+            Some(id) if !id.is_none() => Some(id).filter(|id| self.sessions.contains_key(id)),
+            _ => self.addresses.get(&remote).copied(),
+        }
+    }
+
+    fn insert(&mut self, session: Session) -> Option<Session> {
+        let previous = self
+            .addresses
+            .insert(session.remote(), session.connection_id())
+            .and_then(|id| self.sessions.remove(&id));
+        self.sessions.insert(session.connection_id(), session);
+        previous
+    }
+
+    /// Hands a packet from `remote` to session `id`, following the session to its new address
+    /// if the packet completed a migration
+    fn forward(&mut self, id: ConnectionId, packet: Packet, remote: SocketAddr, size: usize) {
+        let session = self.sessions.get_mut(&id).unwrap();
+        let previous_remote = session.remote();
+        session.on_packet(packet, remote, size);
+        let current_remote = session.remote();
+        if current_remote != previous_remote {
+            // migrated, the old address no longer leads here
+            self.addresses.remove(&previous_remote);
+            self.addresses.insert(current_remote, id);
+        }
+    }
+
+    fn remove(&mut self, id: ConnectionId) -> Option<Session> {
+        let session = self.sessions.remove(&id)?;
+        if self.addresses.get(&session.remote()) == Some(&id) {
+            self.addresses.remove(&session.remote());
+        }
+        Some(session)
+    }
+
+    /// Ends a connected session, returning it so it can still be resumed
+    fn disconnect(&mut self, id: ConnectionId, reason: DisconnectReason) -> Option<Session> {
+        let session = self.remove(id)?;
+        let username = session
+            .identity()
+            .map_or("?", |identity| &identity.username);
+        let window = session.replay_window();
+        info!(
+            "{} ({}) disconnected, reason: {}, {}, {} reordered, {} duplicates, {} rejected, \
+             {} malformed",
+            username,
+            session.remote(),
+            reason,
+            session.stats(),
+            window.reordered(),
+            window.duplicates(),
+            session.rejected(),
+            session.malformed()
+        );
+        self.notify(GameEvent::Disconnected {
+            connection_id: id,
+            identity: session.identity().cloned(),
+            reason,
+        });
+        Some(session)
+    }
+
+    /// Removes the session `session` asked to resume, if any
+    fn take_resumable(
+        &mut self,
+        session: &Session,
+        token: &ResumeToken,
+        now: Instant,
+    ) -> Option<Session> {
+        if let Some(i) = self
+            .detached
+            .iter()
+            .position(|previous| previous.resumable_by(session, token, now))
+        {
+            return Some(self.detached.swap_remove(i));
+        }
+        // the client may also have moved to another address, leaving its session behind
+        let id = self
+            .sessions
+            .values()
+            .find(|previous| previous.resumable_by(session, token, now))?
+            .connection_id();
+        self.remove(id)
+    }
+
+    /// Queues a message to every connected session
+    fn broadcast(&mut self, channel: Channel, msg: Message) {
+        for session in self.sessions.values_mut().filter(|s| s.is_connected()) {
+            session
+                .send_on(channel, &msg)
+                .unwrap_or_else(|err| warn!("error queueing broadcast: {}", err));
+        }
     }
 }
 
@@ -52,14 +215,34 @@ async fn async_main() {
 
     let socket = Arc::new(UdpSocket::bind("0.0.0.0:12345").await.unwrap());
     info!("udp socket bound to {}", addr);
-    let state = Arc::new(Mutex::new(State::new()));
-    future::join(
+    let config = Config::from_env().unwrap();
+    info!("{:?}", config);
+    let authenticator = Arc::from(auth::from_config(&config).unwrap());
+    let mut state = State::new(config, authenticator);
+    let events = state.events();
+    let state = Arc::new(Mutex::new(state));
+    future::join3(
         read_socket(state.clone(), socket.clone()),
         tick_loop(state.clone()),
+        game_events(events),
     )
     .await;
 }
 
+/// Game logic reacting to what happens to sessions
+async fn game_events(mut events: mpsc::UnboundedReceiver<GameEvent>) {
+    while let Some(event) = events.next().await {
+        match event {
+            GameEvent::Disconnected {
+                identity, reason, ..
+            } => {
+                let username = identity.map_or_else(|| "?".to_owned(), |id| id.username);
+                debug!("{} left the game, reason: {}", username, reason);
+            }
+        }
+    }
+}
+
 fn main() -> () {
     executor::block_on(async_main());
 }
@@ -68,8 +251,56 @@ async fn tick_loop(state: Arc<Mutex<State>>) -> () {
     loop {
         task::sleep(Duration::from_millis(16)).await;
         let mut state = state.lock().await;
-        let positions = state.sessions.values().map(|s| s.pos()).collect();
-        state.broadcast(Message::Refresh({ positions })).await
+        let positions = state
+            .sessions
+            .values()
+            .filter(|s| s.is_connected())
+            .map(|s| s.pos())
+            .collect();
+        // Only the latest positions matter, older refreshes are dropped by the client
+        state.broadcast(
+            Channel::UnreliableSequenced,
+            Message::Refresh({ positions }),
+        );
+        for session in state.sessions.values_mut() {
+            session
+                .tick()
+                .await
+                .unwrap_or_else(|err| warn!("error flushing session: {}", err));
+        }
+        let now = Instant::now();
+        state.sessions.retain(|_, session| {
+            let expired = session.handshake_expired(now);
+            if expired {
+                info!(
+                    "handshake with {} timed out, dropping session",
+                    session.remote()
+                );
+            }
+            !expired
+        });
+        let State {
+            sessions,
+            addresses,
+            ..
+        } = &mut *state;
+        addresses.retain(|_, id| sessions.contains_key(id));
+        let idle: Vec<ConnectionId> = state
+            .sessions
+            .values()
+            .filter(|session| session.idle(now))
+            .map(Session::connection_id)
+            .collect();
+        for id in idle {
+            // the client may just have lost its connection for a while
+            if let Some(mut session) = state.disconnect(id, DisconnectReason::Timeout) {
+                session.detach();
+                state.detached.push(session);
+            }
+        }
+        state
+            .detached
+            .retain(|session| !session.resume_expired(now));
     }
 }
 
@@ -81,17 +312,98 @@ async fn read_socket(state: Arc<Mutex<State>>, socket: Arc<UdpSocket>) -> () {
         trace!("RECV <bytes> from {}:\n{}", remote, hexdump(&dgram));
         let mut state = state.lock().await;
         trace!("acquired read lock on server state");
-        let session = state
-            .sessions
-            .entry(remote)
-            .or_insert_with(|| Session::new(remote, socket.clone()));
-        match Packet::from_bytes(dgram) {
+        let id = state.lookup(Packet::peek_connection_id(&dgram), remote);
+        let existing = id.and_then(|id| state.sessions.get(&id));
+        let cipher = existing.and_then(Session::cipher);
+        let mut reconnecting = false;
+        let decoded = match Packet::decode(dgram.clone(), cipher) {
+            // a plaintext packet for an encrypted session, possibly the client starting over
+            // after losing its keys, which has to go through `admit` like a new client. While the
+            // session is still active it is more likely a late or replayed `Connect`.
+            Err(PacketError::AuthenticationFailed)
+                if existing.is_some_and(|session| session.restartable(Instant::now())) =>
+            {
+                reconnecting = true;
+                Packet::from_bytes(dgram)
+            }
+            decoded => decoded,
+        };
+        match decoded {
             Ok(packet) => {
+                let id = match id.filter(|_| !reconnecting) {
+                    Some(id) => id,
+                    None => match admit(&state.cookies, remote, &packet) {
+                        Admission::Accept => {
+                            let id = state.connection_id();
+                            info!("cookie verified, creating session {} for {}", id, remote);
+                            let session = Session::new(
+                                id,
+                                remote,
+                                socket.clone(),
+                                state.config.clone(),
+                                state.occupancy.clone(),
+                                state.authenticator.clone(),
+                            );
+                            // only a connected session has anything worth resuming
+                            match state.insert(session) {
+                                Some(mut previous) if previous.is_connected() => {
+                                    info!(
+                                        "{} started over, detaching its previous session",
+                                        remote
+                                    );
+                                    previous.detach();
+                                    state.detached.push(previous);
+                                }
+                                Some(_) => {
+                                    info!(
+                                        "{} started over, dropping its previous handshake",
+                                        remote
+                                    )
+                                }
+                                None => {}
+                            }
+                            id
+                        }
+                        Admission::Challenge(cookie) => {
+                            send_cookie(&socket, remote, &packet, cookie, size).await;
+                            continue;
+                        }
+                        Admission::Ignore => {
+                            trace!("ignoring packet from {} without a session", remote);
+                            continue;
+                        }
+                    },
+                };
                 trace!("valid packet, forwarding");
-                session.on_packet(packet).await;
-                if session.disconnected() {
-                    info!("client disconnected");
-                    state.sessions.remove(&remote);
+                state.forward(id, packet, remote, size);
+                let resume_request = state.sessions.get_mut(&id).unwrap().take_resume_request();
+                if let Some(token) = resume_request {
+                    let mut session = state.sessions.remove(&id).unwrap();
+                    match state.take_resumable(&session, &token, Instant::now()) {
+                        Some(previous) => session.resume(previous),
+                        None => info!("{} cannot resume its session, starting afresh", remote),
+                    }
+                    state.sessions.insert(id, session);
+                }
+                if state.sessions[&id].disconnected() {
+                    if let Some(mut session) = state.disconnect(id, DisconnectReason::Requested) {
+                        session
+                            .flush()
+                            .await
+                            .unwrap_or_else(|err| warn!("error answering disconnect: {}", err));
+                    }
+                } else if state.sessions[&id].misbehaving() {
+                    warn!("dropping {}, it keeps sending malformed messages", remote);
+                    state.disconnect(id, DisconnectReason::Malformed);
+                }
+            }
+            Err(PacketError::UnsupportedVersion { version }) => {
+                warn!(
+                    "rejecting {}: unsupported protocol version {}",
+                    remote, version
+                );
+                if let Err(err) = socket.send_to(&packet::version_rejection(), remote).await {
+                    warn!("error sending rejection: {}", err);
                 }
             }
             Err(err) => {
@@ -100,3 +412,182 @@ async fn read_socket(state: Arc<Mutex<State>>, socket: Arc<UdpSocket>) -> () {
         }
     }
 }
+
+enum Admission {
+    /// The packet carries a `Connect` with a valid cookie
+    Accept,
+    /// The packet carries a `Connect` without a valid cookie, which gets answered with this one
+    Challenge(Cookie),
+    Ignore,
+}
+
+/// Encoded size of a `Connect` with bincode: the variant index and the cookie
+const CONNECT_SIZE: usize = 4 + std::mem::size_of::<Cookie>();
+
+/// Decides what to do with a packet from an address that has no session, without allocating
+/// anything for it
+fn admit(cookies: &CookieJar, remote: SocketAddr, packet: &Packet) -> Admission {
+    let chunks = match Chunk::decode(packet.message.clone()) {
+        Ok(chunks) => chunks,
+        Err(_) => return Admission::Ignore,
+    };
+    // nothing but a `Connect` is of interest, so anything larger is not even decoded
+    let limits = DecodeLimits::new(0).with_limit(MessageKind::Connect, CONNECT_SIZE);
+    for chunk in chunks.iter().filter(|chunk| chunk.fragment.is_whole()) {
+        // sessions only switch codecs after the handshake
+        if let Ok(Message::Connect { cookie }) = Bincode.decode_limited(&chunk.payload, &limits) {
+            let now = SystemTime::now();
+            return if cookies.verify(remote, &cookie, now) {
+                Admission::Accept
+            } else {
+                Admission::Challenge(cookies.issue(remote, now))
+            };
+        }
+    }
+    Admission::Ignore
+}
+
+/// Answers a connection request with a cookie, outside of any session. The reply uses the
+/// compact header and is never larger than the request, so it cannot be used for amplification.
+async fn send_cookie(
+    socket: &UdpSocket,
+    remote: SocketAddr,
+    request: &Packet,
+    cookie: Cookie,
+    request_size: usize,
+) {
+    let reply = Bincode.encode(&Message::Cookie(cookie)).map(|payload| {
+        let chunk = Chunk {
+            channel: Channel::Unreliable,
+            channel_sequence: SequenceNumber(0),
+            fragment: Fragment::WHOLE,
+            payload: Bytes::from(payload),
+        };
+        Packet::new(
+            SequenceNumber(0),
+            (request.sequence_number, 0),
+            Chunk::encode(&[chunk]),
+        )
+        .with_clock_synced(true)
+    });
+    let bytes = match reply
+        .map_err(Into::into)
+        .and_then(|packet| packet.to_bytes(HeaderFormat::Compact))
+    {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            warn!("error encoding cookie: {}", err);
+            return;
+        }
+    };
+    if bytes.len() > request_size {
+        warn!("not sending cookie to {}, request was too small", remote);
+        return;
+    }
+    trace!("sending cookie to {}", remote);
+    if let Err(err) = socket.send_to(&bytes, remote).await {
+        warn!("error sending cookie: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(sequence: u32, message: &Message) -> Packet {
+        let chunk = Chunk {
+            channel: Channel::Unreliable,
+            channel_sequence: SequenceNumber(0),
+            fragment: Fragment::WHOLE,
+            payload: Bytes::from(Bincode.encode(message).unwrap()),
+        };
+        Packet::new(
+            SequenceNumber(sequence),
+            (SequenceNumber(0), 0),
+            Chunk::encode(&[chunk]),
+        )
+    }
+
+    #[test]
+    fn test_disconnect_event() {
+        let mut state = State::new(Config::default(), Arc::new(auth::Anonymous));
+        let mut events = state.events();
+        let socket = task::block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+        let id = state.connection_id();
+        let session = Session::new(
+            id,
+            SocketAddr::from(([127, 0, 0, 1], 4000)),
+            Arc::new(socket),
+            state.config.clone(),
+            state.occupancy.clone(),
+            state.authenticator.clone(),
+        );
+        state.insert(session);
+
+        assert!(state.disconnect(id, DisconnectReason::Timeout).is_some());
+        assert_eq!(
+            events.try_next().unwrap(),
+            Some(GameEvent::Disconnected {
+                connection_id: id,
+                identity: None,
+                reason: DisconnectReason::Timeout,
+            })
+        );
+        // nothing left to disconnect
+        assert!(state.disconnect(id, DisconnectReason::Timeout).is_none());
+        assert!(events.try_next().is_err());
+    }
+
+    #[test]
+    fn test_admit() {
+        let cookies = CookieJar::new();
+        let remote = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let cookie = cookies.issue(remote, SystemTime::now());
+        match admit(&cookies, remote, &request(0, &Message::Connect { cookie })) {
+            Admission::Accept => {}
+            _ => panic!("valid cookie not accepted"),
+        }
+        match admit(
+            &cookies,
+            remote,
+            &request(0, &Message::Connect { cookie: [0; 32] }),
+        ) {
+            Admission::Challenge(_) => {}
+            _ => panic!("invalid cookie not challenged"),
+        }
+        match admit(&cookies, remote, &request(0, &Message::Heartbeat)) {
+            Admission::Ignore => {}
+            _ => panic!("heartbeat admitted"),
+        }
+    }
+
+    #[test]
+    fn test_migration() {
+        let mut state = State::new(Config::default(), Arc::new(auth::Anonymous));
+        let old = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let client = task::block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+        let new = client.local_addr().unwrap();
+        let session = session::tests::connected(old, "alice");
+        let id = session.connection_id();
+        state.insert(session);
+
+        // the client's packets start arriving from another address, which gets challenged
+        state.forward(id, request(1, &Message::Heartbeat), new, 0);
+        let session = state.sessions.get_mut(&id).unwrap();
+        task::block_on(session.tick()).unwrap();
+        let mut buffer = [0u8; 1500];
+        let (size, _) = task::block_on(client.recv_from(&mut buffer)).unwrap();
+        let packet = Packet::decode(Bytes::from(&buffer[..size]), None).unwrap();
+        let chunks = Chunk::decode(packet.message).unwrap();
+        let data = match Bincode.decode(&chunks[0].payload).unwrap() {
+            Message::PathChallenge(data) => data,
+            other => panic!("unexpected message {:?}", other),
+        };
+        assert_eq!(state.lookup(None, old), Some(id));
+
+        state.forward(id, request(2, &Message::PathResponse(data)), new, 0);
+        assert_eq!(state.sessions[&id].remote(), new);
+        assert_eq!(state.lookup(None, new), Some(id));
+        assert_eq!(state.lookup(None, old), None);
+    }
+}
@@ -3,21 +3,138 @@ use log::{debug, error, info, trace, warn};
 use async_std::net::UdpSocket;
 use async_std::sync::Arc;
 use bytes::Bytes;
+use chrono::Utc;
+use futures::channel::mpsc;
 use rand::random;
-use shared::{handshake::*, hexdump, packet::Packet, proto::*};
+use shared::{
+    ack::*, channel::*, codec::*, crypto::*, handshake::*, hexdump, outbox::*, packet::*, proto::*,
+    stats::*, window::*,
+};
 use std::error::Error;
+use std::fmt;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::auth::{Authenticator, Identity};
+use crate::config::Config;
+
+/// Interval at which the challenge is sent again until the client answers it
+const CHALLENGE_RETRANSMIT: Duration = Duration::from_millis(500);
+
+/// Number of times a path challenge is sent before giving up on the new address
+const PATH_CHALLENGE_ATTEMPTS: u32 = 5;
+
+/// Number of sessions past `on_connect`, shared by every session to enforce
+/// `Config::max_sessions`
+#[derive(Debug, Clone, Default)]
+pub struct Occupancy(Arc<AtomicUsize>);
+
+impl Occupancy {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Takes a slot unless `max` are taken already. The slot is released when the returned
+    /// guard is dropped.
+    fn acquire(&self, max: usize) -> Option<Slot> {
+        let mut count = self.count();
+        while count < max {
+            match self
+                .0
+                .compare_exchange(count, count + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Some(Slot(self.0.clone())),
+                Err(actual) => count = actual,
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug)]
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Why a session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client sent `Disconnect`
+    Requested,
+    /// Nothing was heard from the client for `Config::idle_timeout`
+    Timeout,
+    /// The client sent more than `Config::max_malformed` malformed messages
+    Malformed,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            DisconnectReason::Requested => "requested",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::Malformed => "malformed messages",
+        })
+    }
+}
+
+/// Validation of a new address the client's packets arrived from, see `Message::PathChallenge`
+#[derive(Debug)]
+struct PathProbe {
+    remote: SocketAddr,
+    data: u64,
+    sent: Option<Instant>,
+    attempts: u32,
+}
 
 #[derive(Debug)]
 pub struct Session {
-    client_sequence: u32,
-    server_sequence: u32,
+    connection_id: ConnectionId,
+    window: ReplayWindow,
+    server_sequence: SequenceNumber,
+    acks: AckTracker,
+    delivery_tx: Option<mpsc::UnboundedSender<Delivery>>,
+    channels: Channels,
+    reassembler: Reassembler,
+    outbox: Outbox,
+    config: Arc<Config>,
+    occupancy: Occupancy,
+    authenticator: Arc<dyn Authenticator>,
+    /// Who the client authenticated as, set once connected
+    identity: Option<Identity>,
+    /// Token handed out on `Success`, which lets the client take over this session later
+    resume_token: Option<ResumeToken>,
+    /// Token the client presented to take over an earlier session, see `take_resume_request`
+    resume_request: Option<ResumeToken>,
+    /// Held from `on_connect` until the session is dropped or the handshake fails
+    slot: Option<Slot>,
+    /// Codec for messages after the handshake, see `CodecKind`
+    codec: CodecKind,
+    /// Set once keys have been agreed on, after which every packet is encrypted
+    cipher: Option<Cipher>,
     remote: SocketAddr,
+    /// Address the client seems to have moved to, which is being validated
+    path_probe: Option<PathProbe>,
     // rx: chan::UnboundedReceiver<SessionMessage>,
     socket: Arc<UdpSocket>,
     handshake: HandshakeState,
+    /// When the session was created, which bounds the time the handshake may take
+    created: Instant,
+    /// When the challenge was last sent
+    challenge_sent: Instant,
+    /// When a packet was last accepted from the client
+    last_received: Instant,
     pos: (f32, f32),
     disconnected: bool,
+    /// Messages not allowed in the handshake state they arrived in
+    rejected: u64,
+    /// Payloads and messages that could not be decoded
+    malformed: u64,
+    stats: ConnectionStats,
 }
 
 impl Session {
@@ -25,80 +142,466 @@ impl Session {
         self.pos
     }
 
-    pub fn new(remote: SocketAddr, socket: Arc<UdpSocket>) -> Session {
+    pub fn new(
+        connection_id: ConnectionId,
+        remote: SocketAddr,
+        socket: Arc<UdpSocket>,
+        config: Arc<Config>,
+        occupancy: Occupancy,
+        authenticator: Arc<dyn Authenticator>,
+    ) -> Session {
+        let now = Instant::now();
         Session {
+            connection_id,
             remote,
+            path_probe: None,
             socket,
-            client_sequence: 0,
-            server_sequence: 1,
+            window: ReplayWindow::new(),
+            server_sequence: SequenceNumber(1),
+            acks: AckTracker::new(),
+            delivery_tx: None,
+            channels: Channels::new(),
+            reassembler: Reassembler::default(),
+            outbox: Outbox::new(),
+            config,
+            occupancy,
+            authenticator,
+            identity: None,
+            resume_token: None,
+            resume_request: None,
+            slot: None,
+            codec: CodecKind::Bincode,
+            cipher: None,
             handshake: HandshakeState::Disconnected,
+            created: now,
+            challenge_sent: now,
+            last_received: now,
             pos: (0.0, 0.0),
             disconnected: false,
+            rejected: 0,
+            malformed: 0,
+            stats: ConnectionStats::new(),
         }
     }
 
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    /// Address packets are sent to, which changes once the client is validated at a new one
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
     pub fn disconnected(&self) -> bool {
         self.disconnected
     }
 
-    pub async fn on_packet(&mut self, packet: Packet) -> () {
-        if packet.sequence_number > self.client_sequence {
-            trace!("RECV {:?}", packet);
-            self.client_sequence = packet.sequence_number;
-            let message = bincode::deserialize::<Message>(&packet.message).unwrap();
-            debug!("RECV {:?}", message);
-            match message {
-                Message::Connect => {
-                    self.on_connect().await;
-                }
-                Message::Handshake(handshake_msg) => {
-                    self.on_handshake_message(handshake_msg).await;
-                }
-                Message::Heartbeat => {
-                    self.send(&Message::Heartbeat).await.unwrap();
-                }
-                Message::Move { dx, dy } => {
-                    self.pos = (self.pos.0 + dx, self.pos.1 + dy);
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.handshake == HandshakeState::Connected
+    }
+
+    /// Whether the client failed to complete the handshake in time, after which the session
+    /// should be dropped
+    pub fn handshake_expired(&self, now: Instant) -> bool {
+        !self.is_connected() && now.duration_since(self.created) > self.config.handshake_timeout
+    }
+
+    /// Token the client presented during the handshake, to be passed to `resume` along with
+    /// the session it belongs to
+    pub fn take_resume_request(&mut self) -> Option<ResumeToken> {
+        self.resume_request.take()
+    }
+
+    /// Whether `session` may take over this session with `token`: the token is ours, both
+    /// belong to the same player, and the client was last heard from within the grace period
+    pub fn resumable_by(&self, session: &Session, token: &ResumeToken, now: Instant) -> bool {
+        self.resume_token.as_ref() == Some(token)
+            && self.identity.is_some()
+            && self.identity == session.identity
+            && now.duration_since(self.last_received) <= self.config.resume_grace
+    }
+
+    /// Picks up where `previous` left off, after the client reconnected. The position carries
+    /// over, and so do our sequence numbers. The replay window, channels and keys belong to the
+    /// new handshake, which the client went through with fresh ones too.
+    pub fn resume(&mut self, previous: Session) {
+        info!("{} resumed the session of {}", self.remote, previous.remote);
+        self.pos = previous.pos;
+        // carry on with the sequence numbers of the previous session, starting with the
+        // success that is still waiting to be flushed
+        if previous.server_sequence > self.server_sequence {
+            self.server_sequence = previous.server_sequence;
+        }
+    }
+
+    /// Releases the slot of a session whose client has started over or gone quiet, which is
+    /// kept around for `Config::resume_grace` in case it wants to resume it
+    pub fn detach(&mut self) {
+        self.slot = None;
+    }
+
+    /// Whether a new connection from the client's address may replace this session: either the
+    /// handshake has not completed, or the client has gone quiet for `Config::restart_after`
+    pub fn restartable(&self, now: Instant) -> bool {
+        !self.is_connected() || now.duration_since(self.last_received) > self.config.restart_after
+    }
+
+    /// Whether a detached session may still be resumed
+    pub fn resume_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.last_received) > self.config.resume_grace
+    }
+
+    /// Whether the client has gone quiet for longer than `Config::idle_timeout`, after which
+    /// the session should be evicted
+    pub fn idle(&self, now: Instant) -> bool {
+        self.is_connected() && now.duration_since(self.last_received) > self.config.idle_timeout
+    }
+
+    /// Number of messages rejected because the handshake had not reached the right state
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Number of payloads and messages dropped because they could not be decoded, or exceeded
+    /// the size limits
+    pub fn malformed(&self) -> u64 {
+        self.malformed
+    }
+
+    /// Whether the client sent more malformed messages than we put up with, and should be
+    /// dropped
+    pub fn misbehaving(&self) -> bool {
+        self.config
+            .max_malformed
+            .is_some_and(|max| self.malformed > max)
+    }
+
+    fn on_malformed(&mut self, what: &str, err: &dyn fmt::Display) {
+        self.malformed += 1;
+        warn!(
+            "dropping {} from {}: {} ({} malformed so far)",
+            what, self.remote, err, self.malformed
+        );
+    }
+
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref()
+    }
+
+    /// Encrypts every packet from now on, and requires incoming packets to be encrypted too
+    pub fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+
+    /// Latency, loss and throughput of the connection so far
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    pub fn replay_window(&self) -> &ReplayWindow {
+        &self.window
+    }
+
+    /// Stream of delivery notifications for packets sent to this client. Only the most recent
+    /// receiver gets notified.
+    // for game logic on top of sessions, none of which needs it yet
+    #[allow(dead_code)]
+    pub fn deliveries(&mut self) -> mpsc::UnboundedReceiver<Delivery> {
+        let (tx, rx) = mpsc::unbounded();
+        self.delivery_tx = Some(tx);
+        rx
+    }
+
+    fn notify(&mut self, delivery: Delivery) {
+        trace!("{:?}", delivery);
+        if let Some(tx) = &self.delivery_tx {
+            if tx.unbounded_send(delivery).is_err() {
+                // receiver went away
+                self.delivery_tx = None;
+            }
+        }
+    }
+
+    /// Handles a packet received from `from`, which differs from `remote` when the client's
+    /// address has changed
+    pub fn on_packet(&mut self, packet: Packet, from: SocketAddr, size: usize) -> () {
+        // the client only stamps packets with our clock once it has synchronized with it, which
+        // takes a few round trips after the handshake, see `ReplayWindow::accept_packet`
+        let bounds = &self.config.timestamp_bounds;
+        let acceptance = match self.window.accept_packet(&packet, bounds, Utc::now()) {
+            Ok(acceptance) => acceptance,
+            Err(err) => {
+                warn!(
+                    "dropping packet {} from {}: {} ({} expired, {} premature, {} unsynced so far)",
+                    packet.sequence_number,
+                    from,
+                    err,
+                    self.window.expired(),
+                    self.window.premature(),
+                    self.window.unsynced()
+                );
+                return;
+            }
+        };
+        if acceptance.is_accepted() {
+            trace!("RECV {:?} ({:?})", packet, acceptance);
+            let now = Instant::now();
+            self.last_received = now;
+            self.stats.on_receive(packet.timestamp, size, Utc::now());
+            // only the newest packets count, a reordered one may come from an old address
+            if from != self.remote && acceptance == Acceptance::InOrder && self.is_connected() {
+                self.probe_path(from);
+            }
+            for delivery in self.acks.on_ack(packet.ack, packet.ack_bits) {
+                self.stats.on_delivery(delivery, now);
+                self.channels.on_delivery(delivery);
+                self.notify(delivery);
+            }
+            let chunks = match Chunk::decode(packet.message) {
+                Ok(chunks) => chunks,
+                Err(err) => {
+                    self.on_malformed("packet with malformed payload", &err);
+                    return;
                 }
-                Message::Disconnect => {
-                    self.disconnected = true;
+            };
+            let now = Instant::now();
+            for chunk in chunks {
+                let chunk = match self.reassembler.reassemble(chunk, now) {
+                    Ok(Some(chunk)) => chunk,
+                    // waiting for the remaining fragments
+                    Ok(None) => continue,
+                    Err(err) => {
+                        self.on_malformed("fragmented message", &err);
+                        continue;
+                    }
+                };
+                let payloads =
+                    self.channels
+                        .incoming(chunk.channel, chunk.channel_sequence, chunk.payload);
+                for payload in payloads {
+                    let limits = &self.config.decode_limits;
+                    let message = match self.codec.decode_limited(&payload, limits) {
+                        Ok(message) => message,
+                        Err(err) => {
+                            self.on_malformed("message", &err);
+                            continue;
+                        }
+                    };
+                    debug!("RECV {:?}", message);
+                    self.on_message(message, from);
                 }
-                _ => {}
             }
         } else {
-            warn!("sequence out of sync, ignoring packet");
+            warn!(
+                "{:?} packet {}, ignoring ({} duplicates so far)",
+                acceptance,
+                packet.sequence_number,
+                self.window.duplicates()
+            );
         }
     }
 
-    async fn on_connect(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
-        let nonce = random::<u32>();
-        if self.handshake == HandshakeState::Disconnected {
-            self.handshake = HandshakeState::Negotiating { nonce };
-            self.send(&Message::Handshake(HandshakeMessage::Challenge(nonce)))
-                .await?;
-        } else {
-            // Handshake is already in progress, ignore packet
-            warn!("duplicate connection attempt, handshake already in progress");
+    fn on_message(&mut self, message: Message, from: SocketAddr) {
+        if !self.handshake.allows(message.kind()) {
+            self.rejected += 1;
+            warn!(
+                "rejecting {:?} from {} in state {:?} ({} rejected so far)",
+                message.kind(),
+                self.remote,
+                self.handshake,
+                self.rejected
+            );
+            return;
+        }
+        match message {
+            Message::Connect { .. } => {
+                self.on_connect()
+                    .unwrap_or_else(|err| warn!("error answering connect: {}", err));
+            }
+            Message::Handshake(handshake_msg) => {
+                self.on_handshake_message(handshake_msg);
+            }
+            Message::Heartbeat => {
+                self.send(&Message::Heartbeat)
+                    .unwrap_or_else(|err| warn!("error answering heartbeat: {}", err));
+            }
+            Message::TimeRequest(client_sent) => {
+                let response = Message::TimeResponse {
+                    client_sent,
+                    server_received: Utc::now(),
+                };
+                self.send(&response)
+                    .unwrap_or_else(|err| warn!("error answering time request: {}", err));
+            }
+            Message::Move { dx, dy } => {
+                self.pos = (self.pos.0 + dx, self.pos.1 + dy);
+            }
+            Message::Disconnect => {
+                self.disconnected = true;
+                // carries the ack for the client's disconnect, which it waits for
+                self.send(&Message::Disconnect)
+                    .unwrap_or_else(|err| warn!("error answering disconnect: {}", err));
+            }
+            Message::PathResponse(data) => {
+                self.on_path_response(data, from);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connect(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        match &self.handshake {
+            HandshakeState::Disconnected => {
+                if self.config.banned.contains(&self.remote.ip()) {
+                    info!("refusing banned client {}", self.remote);
+                    return self.reject(FailureReason::Banned);
+                }
+                self.slot = self.occupancy.acquire(self.config.max_sessions);
+                if self.slot.is_none() {
+                    info!("refusing {}, server is full", self.remote);
+                    return self.reject(FailureReason::ServerFull);
+                }
+                let exchange = KeyExchange::new();
+                let public_key = exchange.public_key();
+                self.handshake = HandshakeState::Negotiating { exchange };
+                self.send_challenge(public_key, Instant::now())?;
+            }
+            HandshakeState::Negotiating { exchange } => {
+                // our challenge was probably lost, answer with the same one
+                debug!("duplicate connection attempt, sending challenge again");
+                let public_key = exchange.public_key();
+                self.send_challenge(public_key, Instant::now())?;
+            }
+            HandshakeState::Connected => {
+                warn!("duplicate connection attempt, already connected");
+            }
         }
         Ok(())
     }
 
-    async fn on_handshake_message(&mut self, msg: HandshakeMessage) {
+    /// Abandons the handshake, freeing the session slot
+    fn fail_handshake(&mut self, reason: FailureReason) {
+        self.handshake = HandshakeState::Disconnected;
+        self.slot = None;
+        error!("connection transitioned to DISCONNECTED");
+        self.reject(reason)
+            .unwrap_or_else(|err| warn!("error sending failure: {}", err));
+    }
+
+    /// Tells the client why its connection was refused
+    fn reject(&mut self, reason: FailureReason) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.send_reliable(&Message::Handshake(HandshakeMessage::Failure(reason)))
+    }
+
+    /// Sends the challenge unreliably. It is retransmitted on a timer until the client answers,
+    /// rather than until it is acknowledged.
+    fn send_challenge(
+        &mut self,
+        public_key: PublicKey,
+        now: Instant,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.challenge_sent = now;
+        self.send(&Message::Handshake(HandshakeMessage::Challenge {
+            public_key,
+            connection_id: self.connection_id,
+        }))
+    }
+
+    /// Starts validating `remote` as the client's new address, unless that is underway already.
+    /// Packets keep going to the old address until the client answers.
+    fn probe_path(&mut self, remote: SocketAddr) {
+        if self.path_probe.as_ref().map(|probe| probe.remote) == Some(remote) {
+            return;
+        }
+        info!(
+            "{} sent packets from {}, validating the new address",
+            self.connection_id, remote
+        );
+        self.path_probe = Some(PathProbe {
+            remote,
+            data: random(),
+            sent: None,
+            attempts: 0,
+        });
+    }
+
+    fn on_path_response(&mut self, data: u64, from: SocketAddr) {
+        match &self.path_probe {
+            Some(probe) if probe.remote == from && probe.data == data => {
+                info!(
+                    "{} migrated from {} to {}",
+                    self.connection_id, self.remote, from
+                );
+                self.remote = from;
+                self.path_probe = None;
+            }
+            _ => warn!("unexpected path response from {}", from),
+        }
+    }
+
+    fn on_handshake_message(&mut self, msg: HandshakeMessage) {
         match (self.handshake.clone(), msg) {
-            (HandshakeState::Negotiating { nonce }, HandshakeMessage::Challenge(ack)) => {
-                if nonce == ack {
-                    // challenge authorized
-                    self.handshake = HandshakeState::Connected;
-                    info!("connection transitioned to CONNECTED");
-                    self.send(&Message::Handshake(HandshakeMessage::Success))
-                        .await;
-                } else {
-                    // invalid nonce
-                    warn!("received nonce differs");
-                    self.handshake = HandshakeState::Disconnected;
-                    error!("connection transitioned to DISCONNECTED");
-                    self.send(&Message::Handshake(HandshakeMessage::Failure))
-                        .await;
+            (
+                HandshakeState::Negotiating { exchange },
+                HandshakeMessage::Response {
+                    public_key,
+                    proof,
+                    credentials,
+                    codec,
+                },
+            ) => {
+                let keys = exchange.derive(Role::Server, &public_key);
+                if !keys.verify(&proof) {
+                    // client derived different keys
+                    warn!("invalid key proof");
+                    self.fail_handshake(FailureReason::BadChallenge);
+                    return;
+                }
+                let login =
+                    keys.open_credentials(&credentials)
+                        .and_then(|(credentials, resume)| {
+                            let identity = self
+                                .authenticator
+                                .authenticate(&credentials, keys.transcript())?;
+                            Some((identity, resume))
+                        });
+                match login {
+                    Some((identity, resume)) => {
+                        // client holds the session keys, everything from now on is encrypted
+                        self.set_cipher(keys.cipher());
+                        self.handshake = HandshakeState::Connected;
+                        info!(
+                            "connection transitioned to CONNECTED, {} authenticated as {}",
+                            self.remote, identity.username
+                        );
+                        self.identity = Some(identity);
+                        self.resume_request = resume;
+                        let resume = random();
+                        self.resume_token = Some(resume);
+                        let codec = if self.config.codecs.contains(&codec) {
+                            codec
+                        } else {
+                            CodecKind::Bincode
+                        };
+                        self.send_reliable(&Message::Handshake(HandshakeMessage::Success {
+                            proof: keys.proof(),
+                            resume,
+                            codec,
+                        }))
+                        .unwrap_or_else(|err| warn!("error sending success: {}", err));
+                        // the success itself is still encoded the way the handshake was
+                        debug!("{} uses the {} codec", self.connection_id, codec);
+                        self.codec = codec;
+                    }
+                    None => {
+                        warn!("invalid credentials from {}", self.remote);
+                        self.fail_handshake(FailureReason::BadCredentials);
+                    }
                 }
             }
             (state, msg) => {
@@ -107,19 +610,350 @@ impl Session {
         }
     }
 
-    pub async fn send(&mut self, msg: &Message) -> Result<(), Box<dyn Error + Sync + Send>> {
-        let data = Bytes::from(bincode::serialize(&msg)?);
-        debug!("SEND {:?}", msg);
-        let packet = Packet::new(self.server_sequence, data);
-        let wire_bytes = packet.to_bytes()?;
+    /// Queues a message, to be sent on the next flush
+    pub fn send(&mut self, msg: &Message) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.send_on(Channel::Unreliable, msg)
+    }
+
+    /// Queues a message that is retransmitted until acknowledged and delivered in order
+    pub fn send_reliable(&mut self, msg: &Message) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.send_on(Channel::ReliableOrdered, msg)
+    }
+
+    pub fn send_on(
+        &mut self,
+        channel: Channel,
+        msg: &Message,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let data = Bytes::from(self.codec.encode(msg)?);
+        debug!("SEND {:?} on {:?}", msg, channel);
+        let channel_sequence = self.channels.outgoing(channel, &data, Instant::now());
+        self.outbox.push(channel, channel_sequence, data)?;
+        Ok(())
+    }
+
+    /// Queues reliable messages that were lost or have not been acknowledged in time, discards
+    /// incomplete fragmented messages that have timed out, and flushes the outgoing queue
+    pub async fn tick(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let now = Instant::now();
+        if let HandshakeState::Negotiating { exchange } = &self.handshake {
+            if now.duration_since(self.challenge_sent) >= CHALLENGE_RETRANSMIT {
+                trace!("retransmitting challenge to {}", self.remote);
+                let public_key = exchange.public_key();
+                self.send_challenge(public_key, now)?;
+            }
+        }
+        self.send_path_challenge(now).await?;
+        let expired = self.reassembler.expire(now);
+        if expired > 0 {
+            warn!("discarded {} incomplete fragmented messages", expired);
+        }
+        for (channel, channel_sequence, data) in self.channels.due(now) {
+            trace!("retransmitting {:?} message {}", channel, channel_sequence);
+            self.outbox.push(channel, channel_sequence, data)?;
+        }
+        self.flush().await
+    }
+
+    /// Sends the path challenge to the address being validated, bypassing the outbox since
+    /// everything else still goes to the old address
+    async fn send_path_challenge(
+        &mut self,
+        now: Instant,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let (remote, data) = match &mut self.path_probe {
+            Some(probe) => {
+                if probe
+                    .sent
+                    .is_some_and(|sent| now.duration_since(sent) < CHALLENGE_RETRANSMIT)
+                {
+                    return Ok(());
+                }
+                if probe.attempts == PATH_CHALLENGE_ATTEMPTS {
+                    warn!(
+                        "{} did not answer at {}, staying at {}",
+                        self.connection_id, probe.remote, self.remote
+                    );
+                    self.path_probe = None;
+                    return Ok(());
+                }
+                probe.sent = Some(now);
+                probe.attempts += 1;
+                (probe.remote, probe.data)
+            }
+            None => return Ok(()),
+        };
+        let payload = Bytes::from(self.codec.encode(&Message::PathChallenge(data))?);
+        let channel_sequence = self.channels.outgoing(Channel::Unreliable, &payload, now);
+        let chunk = Chunk {
+            channel: Channel::Unreliable,
+            channel_sequence,
+            fragment: Fragment::WHOLE,
+            payload,
+        };
+        self.send_packet(vec![chunk], remote).await
+    }
+
+    /// Sends every queued message, packing as many of them into each packet as possible
+    pub async fn flush(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        for chunks in self.outbox.drain() {
+            self.send_packet(chunks, self.remote).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_packet(
+        &mut self,
+        chunks: Vec<Chunk>,
+        remote: SocketAddr,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let packet = Packet::new(
+            self.server_sequence,
+            self.window.ack_header(),
+            Chunk::encode(&chunks),
+        )
+        .with_connection_id(self.connection_id)
+        // our clock is the one clients synchronize with
+        .with_clock_synced(true);
+        let wire_bytes = packet.encode(self.config.header_format, self.cipher.as_ref())?;
         trace!(
-            "SEND to {}\n{:?}\n{}",
-            self.remote,
+            "SEND to {}\n{:?}\n{:?}\n{}",
+            remote,
             packet,
+            chunks,
             hexdump(&wire_bytes)
         );
-        self.socket.send_to(&wire_bytes, self.remote).await?;
-        self.server_sequence += 1;
+        self.socket.send_to(&wire_bytes, remote).await?;
+        for chunk in &chunks {
+            self.channels.on_sent(
+                chunk.channel,
+                chunk.channel_sequence,
+                chunk.fragment,
+                self.server_sequence,
+            );
+        }
+        let now = Instant::now();
+        self.stats
+            .on_send(self.server_sequence, wire_bytes.len(), now);
+        if let Some(lost) = self.acks.on_send(self.server_sequence) {
+            self.stats.on_delivery(lost, now);
+            self.channels.on_delivery(lost);
+            self.notify(lost);
+        }
+        self.server_sequence = self.server_sequence.next();
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::auth::Anonymous;
+    use async_std::task;
+
+    /// Session for a client at `remote`, which has not sent anything yet
+    fn session(remote: SocketAddr) -> Session {
+        let socket = task::block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+        Session::new(
+            ConnectionId(1),
+            remote,
+            Arc::new(socket),
+            Arc::new(Config::default()),
+            Occupancy::default(),
+            Arc::new(Anonymous),
+        )
+    }
+
+    /// Session for a client at `remote` that has authenticated as `username`
+    pub(crate) fn connected(remote: SocketAddr, username: &str) -> Session {
+        let mut session = session(remote);
+        session.handshake = HandshakeState::Connected;
+        session.identity = Some(Identity {
+            username: username.to_owned(),
+        });
+        session
+    }
+
+    /// Packet from the client carrying `message`
+    fn packet(sequence: u32, message: &Message) -> Packet {
+        let chunk = Chunk {
+            channel: Channel::Unreliable,
+            channel_sequence: SequenceNumber(sequence),
+            fragment: Fragment::WHOLE,
+            payload: Bytes::from(Bincode.encode(message).unwrap()),
+        };
+        Packet::new(
+            SequenceNumber(sequence),
+            (SequenceNumber(0), 0),
+            Chunk::encode(&[chunk]),
+        )
+    }
+
+    #[test]
+    fn test_deliveries() {
+        let client = task::block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+        let remote = client.local_addr().unwrap();
+        let mut session = session(remote);
+        let mut deliveries = session.deliveries();
+        session.send(&Message::Heartbeat).unwrap();
+        task::block_on(session.flush()).unwrap();
+
+        // the client acknowledges the server's first packet
+        let ack = Packet::new(
+            SequenceNumber(1),
+            (SequenceNumber(1), 0),
+            Chunk::encode(&[]),
+        );
+        session.on_packet(ack, remote, 0);
+        assert_eq!(
+            deliveries.try_next().unwrap(),
+            Some(Delivery::Acked(SequenceNumber(1)))
+        );
+    }
+
+    #[test]
+    fn test_skewed_client() {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let mut session = connected(remote, "alice");
+        let skewed = Utc::now() - chrono::Duration::hours(1);
+        let packet = |sequence| {
+            Packet::new(
+                SequenceNumber(sequence),
+                (SequenceNumber(0), 0),
+                Chunk::encode(&[]),
+            )
+        };
+
+        // before the client has synchronized its clock, e.g. its time requests
+        session.on_packet(packet(1).with_timestamp(skewed), remote, 0);
+        assert_eq!(session.replay_window().latest(), Some(SequenceNumber(1)));
+        // and after, stamped with an estimate of our clock
+        session.on_packet(packet(2).with_clock_synced(true), remote, 0);
+        assert_eq!(session.replay_window().latest(), Some(SequenceNumber(2)));
+
+        let stale = packet(3).with_timestamp(skewed).with_clock_synced(true);
+        session.on_packet(stale, remote, 0);
+        session.on_packet(packet(4).with_timestamp(skewed), remote, 0);
+        assert_eq!(session.replay_window().latest(), Some(SequenceNumber(2)));
+        assert_eq!(session.replay_window().expired(), 1);
+        assert_eq!(session.replay_window().unsynced(), 1);
+    }
+
+    #[test]
+    fn test_idle() {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let now = Instant::now();
+        let quiet = now + Config::default().idle_timeout + Duration::from_secs(1);
+        let connected = connected(remote, "alice");
+        assert!(!connected.idle(now));
+        assert!(connected.idle(quiet));
+
+        // a handshake in progress times out on its own terms, see `handshake_expired`
+        let mut negotiating = session(remote);
+        negotiating.handshake = HandshakeState::Negotiating {
+            exchange: KeyExchange::new(),
+        };
+        assert!(!negotiating.idle(quiet));
+    }
+
+    #[test]
+    fn test_restartable() {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let now = Instant::now();
+        let quiet = now + Config::default().restart_after + Duration::from_secs(1);
+        let connected = connected(remote, "alice");
+        assert!(!connected.restartable(now));
+        assert!(connected.restartable(quiet));
+        assert!(session(remote).restartable(now));
+    }
+
+    #[test]
+    fn test_resume() {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let mut previous = connected(remote, "alice");
+        previous.resume_token = Some([7; 32]);
+        previous.pos = (3.0, 4.0);
+        previous.server_sequence = SequenceNumber(100);
+        let mut session = connected(SocketAddr::from(([127, 0, 0, 1], 4001)), "alice");
+        let now = Instant::now();
+        assert!(previous.resumable_by(&session, &[7; 32], now));
+        assert!(!previous.resumable_by(&session, &[8; 32], now));
+        assert!(!previous.resumable_by(&connected(remote, "bob"), &[7; 32], now));
+        let expired = now + previous.config.resume_grace + Duration::from_secs(1);
+        assert!(!previous.resumable_by(&session, &[7; 32], expired));
+
+        session.resume(previous);
+        assert_eq!(session.pos(), (3.0, 4.0));
+        assert_eq!(session.server_sequence, SequenceNumber(100));
+    }
+
+    #[test]
+    fn test_path_validation() {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let moved = SocketAddr::from(([127, 0, 0, 1], 4001));
+        let elsewhere = SocketAddr::from(([127, 0, 0, 1], 4002));
+        let mut session = connected(remote, "alice");
+
+        session.on_packet(packet(2, &Message::Heartbeat), moved, 0);
+        let data = match &session.path_probe {
+            Some(probe) if probe.remote == moved => probe.data,
+            other => panic!("unexpected probe {:?}", other),
+        };
+        session.on_packet(
+            packet(3, &Message::PathResponse(data.wrapping_add(1))),
+            moved,
+            0,
+        );
+        assert_eq!(session.remote(), remote);
+        // reordered, so that it does not start probing its own address
+        session.on_packet(packet(1, &Message::PathResponse(data)), elsewhere, 0);
+        assert_eq!(session.remote(), remote);
+        assert!(session.path_probe.is_some());
+
+        session.on_packet(packet(4, &Message::PathResponse(data)), moved, 0);
+        assert_eq!(session.remote(), moved);
+        assert!(session.path_probe.is_none());
+    }
+
+    #[test]
+    fn test_path_probe_gives_up() {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let client = task::block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+        let moved = client.local_addr().unwrap();
+        let mut session = connected(remote, "alice");
+        session.on_packet(packet(1, &Message::Heartbeat), moved, 0);
+
+        let mut now = Instant::now();
+        for attempt in 1..=PATH_CHALLENGE_ATTEMPTS {
+            task::block_on(session.send_path_challenge(now)).unwrap();
+            assert_eq!(session.path_probe.as_ref().unwrap().attempts, attempt);
+            // not sent again until it is due
+            task::block_on(session.send_path_challenge(now)).unwrap();
+            now += CHALLENGE_RETRANSMIT;
+        }
+        task::block_on(session.send_path_challenge(now)).unwrap();
+        assert!(session.path_probe.is_none());
+        assert_eq!(session.remote(), remote);
+    }
+
+    #[test]
+    fn test_occupancy() {
+        let occupancy = Occupancy::default();
+        let first = occupancy.acquire(2);
+        let second = occupancy.acquire(2);
+        assert!(first.is_some() && second.is_some());
+        assert!(occupancy.acquire(2).is_none());
+        drop(first);
+        assert_eq!(occupancy.count(), 1);
+        assert!(occupancy.acquire(2).is_some());
+    }
+
+    #[test]
+    fn test_disconnect_reason() {
+        assert_eq!(DisconnectReason::Timeout.to_string(), "timeout");
+        assert_eq!(
+            DisconnectReason::Malformed.to_string(),
+            "malformed messages"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use crate::packet::SequenceNumber;
+use std::collections::VecDeque;
+
+/// Number of packets preceding `Packet::ack` that are covered by `Packet::ack_bits`
+pub const ACK_BITS: u32 = 32;
+
+/// Upper bound for unacknowledged packets kept around, in case the remote never acks anything
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// Delivery status of a packet we sent, as learned from the acknowledgement header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Acked(SequenceNumber),
+    Lost(SequenceNumber),
+}
+
+/// Tracks sent packets until they are either acknowledged by the remote or considered lost.
+///
+/// A packet is lost once the remote has acked a packet more than `ACK_BITS` newer than it,
+/// since from then on the acknowledgement header can no longer refer to it.
+#[derive(Debug, Default)]
+pub struct AckTracker {
+    in_flight: VecDeque<SequenceNumber>,
+}
+
+impl AckTracker {
+    pub fn new() -> AckTracker {
+        Default::default()
+    }
+
+    pub fn on_send(&mut self, sequence: SequenceNumber) -> Option<Delivery> {
+        self.in_flight.push_back(sequence);
+        if self.in_flight.len() > MAX_IN_FLIGHT {
+            self.in_flight.pop_front().map(Delivery::Lost)
+        } else {
+            None
+        }
+    }
+
+    pub fn on_ack(&mut self, ack: SequenceNumber, ack_bits: u32) -> Vec<Delivery> {
+        let mut deliveries = vec![];
+        self.in_flight.retain(|&sequence| {
+            if sequence > ack {
+                return true;
+            }
+            let offset = ack.distance(sequence);
+            if offset == 0 || (offset <= ACK_BITS && ack_bits & (1 << (offset - 1)) != 0) {
+                deliveries.push(Delivery::Acked(sequence));
+                false
+            } else if offset > ACK_BITS {
+                deliveries.push(Delivery::Lost(sequence));
+                false
+            } else {
+                true
+            }
+        });
+        deliveries
+    }
+
+    /// Number of sent packets whose delivery status is still unknown
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ack_bits() {
+        let mut tracker = AckTracker::new();
+        for seq in 1..=5 {
+            tracker.on_send(SequenceNumber(seq));
+        }
+        // 5 acked directly, 4 and 2 through the bitfield, 3 and 1 still unknown
+        let deliveries = tracker.on_ack(SequenceNumber(5), 0b0101);
+        assert_eq!(
+            deliveries,
+            vec![
+                Delivery::Acked(SequenceNumber(2)),
+                Delivery::Acked(SequenceNumber(4)),
+                Delivery::Acked(SequenceNumber(5)),
+            ]
+        );
+        assert_eq!(tracker.in_flight(), 2);
+    }
+
+    #[test]
+    fn test_lost() {
+        let mut tracker = AckTracker::new();
+        tracker.on_send(SequenceNumber(1));
+        tracker.on_send(SequenceNumber(40));
+        let deliveries = tracker.on_ack(SequenceNumber(40), 0);
+        assert_eq!(
+            deliveries,
+            vec![
+                Delivery::Lost(SequenceNumber(1)),
+                Delivery::Acked(SequenceNumber(40)),
+            ]
+        );
+        assert_eq!(tracker.in_flight(), 0);
+    }
+}
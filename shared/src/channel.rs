@@ -0,0 +1,209 @@
+use crate::ack::Delivery;
+use crate::packet::{Fragment, SequenceNumber};
+use crate::reliable::{ReliableReceiver, ReliableSender};
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::time::Instant;
+
+/// Logical channel a message is sent on. Every channel has its own sequence space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// Fire and forget, messages may be lost, duplicated by the application or reordered
+    Unreliable,
+    /// Messages may be lost, and anything older than the newest received message is dropped
+    UnreliableSequenced,
+    /// Messages are retransmitted until acknowledged and delivered in order
+    ReliableOrdered,
+}
+
+impl Channel {
+    pub fn id(self) -> u8 {
+        match self {
+            Channel::Unreliable => 0,
+            Channel::UnreliableSequenced => 1,
+            Channel::ReliableOrdered => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Channel> {
+        match id {
+            0 => Some(Channel::Unreliable),
+            1 => Some(Channel::UnreliableSequenced),
+            2 => Some(Channel::ReliableOrdered),
+            _ => None,
+        }
+    }
+}
+
+/// Receiving half of an unreliable-sequenced channel, which only lets through messages newer
+/// than anything received before.
+#[derive(Debug, Default)]
+pub struct SequencedReceiver {
+    latest: Option<SequenceNumber>,
+    dropped: u64,
+}
+
+impl SequencedReceiver {
+    pub fn receive(&mut self, sequence: SequenceNumber) -> bool {
+        let newer = match self.latest {
+            None => true,
+            // numbers exactly half the sequence space apart are unordered, which we treat as
+            // older rather than risk letting through a message from the distant past
+            Some(latest) => sequence.partial_cmp(&latest) == Some(Ordering::Greater),
+        };
+        if newer {
+            self.latest = Some(sequence);
+        } else {
+            self.dropped += 1;
+        }
+        newer
+    }
+
+    /// Number of messages dropped because a newer one had already arrived
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Per-connection state of every channel, in both directions
+#[derive(Debug, Default)]
+pub struct Channels {
+    unreliable_tx: SequenceNumber,
+    sequenced_tx: SequenceNumber,
+    sequenced_rx: SequencedReceiver,
+    reliable_tx: ReliableSender,
+    reliable_rx: ReliableReceiver,
+}
+
+impl Channels {
+    pub fn new() -> Channels {
+        Default::default()
+    }
+
+    /// Assigns the next sequence number of `channel` to an outgoing message
+    pub fn outgoing(&mut self, channel: Channel, payload: &Bytes, now: Instant) -> SequenceNumber {
+        match channel {
+            Channel::Unreliable => next(&mut self.unreliable_tx),
+            Channel::UnreliableSequenced => next(&mut self.sequenced_tx),
+            Channel::ReliableOrdered => self.reliable_tx.push(payload.clone(), now),
+        }
+    }
+
+    /// Records that a message, or a fragment of it, was sent in packet `sequence`
+    pub fn on_sent(
+        &mut self,
+        channel: Channel,
+        channel_sequence: SequenceNumber,
+        fragment: Fragment,
+        sequence: SequenceNumber,
+    ) {
+        if channel == Channel::ReliableOrdered {
+            self.reliable_tx
+                .on_sent(channel_sequence, fragment, sequence);
+        }
+    }
+
+    pub fn on_delivery(&mut self, delivery: Delivery) {
+        self.reliable_tx.on_delivery(delivery);
+    }
+
+    /// Routes an incoming message to its channel, returning the messages that can be handed
+    /// over to the application
+    pub fn incoming(
+        &mut self,
+        channel: Channel,
+        channel_sequence: SequenceNumber,
+        payload: Bytes,
+    ) -> Vec<Bytes> {
+        match channel {
+            Channel::Unreliable => vec![payload],
+            Channel::UnreliableSequenced => {
+                if self.sequenced_rx.receive(channel_sequence) {
+                    vec![payload]
+                } else {
+                    vec![]
+                }
+            }
+            Channel::ReliableOrdered => self.reliable_rx.receive(channel_sequence, payload),
+        }
+    }
+
+    /// Reliable messages that need to be sent again
+    pub fn due(&mut self, now: Instant) -> Vec<(Channel, SequenceNumber, Bytes)> {
+        self.reliable_tx
+            .due(now)
+            .into_iter()
+            .map(|(id, payload)| (Channel::ReliableOrdered, id, payload))
+            .collect()
+    }
+
+    pub fn sequenced_rx(&self) -> &SequencedReceiver {
+        &self.sequenced_rx
+    }
+
+    pub fn reliable_tx(&self) -> &ReliableSender {
+        &self.reliable_tx
+    }
+
+    pub fn reliable_rx(&self) -> &ReliableReceiver {
+        &self.reliable_rx
+    }
+}
+
+fn next(sequence: &mut SequenceNumber) -> SequenceNumber {
+    let current = *sequence;
+    *sequence = current.next();
+    current
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequenced() {
+        let mut channels = Channels::new();
+        let payload = Bytes::from_static(b"refresh");
+        for seq in &[1, 3, 2, 3, 4] {
+            channels.incoming(
+                Channel::UnreliableSequenced,
+                SequenceNumber(*seq),
+                payload.clone(),
+            );
+        }
+        assert_eq!(channels.sequenced_rx().dropped(), 2);
+    }
+
+    #[test]
+    fn test_sequenced_unordered() {
+        let mut rx = SequencedReceiver::default();
+        assert!(rx.receive(SequenceNumber(1)));
+        assert!(!rx.receive(SequenceNumber(1).wrapping_add(1 << 31)));
+        assert!(rx.receive(SequenceNumber(2)));
+        assert_eq!(rx.dropped(), 1);
+    }
+
+    #[test]
+    fn test_separate_sequence_spaces() {
+        let now = Instant::now();
+        let mut channels = Channels::new();
+        let payload = Bytes::from_static(b"message");
+        assert_eq!(
+            channels.outgoing(Channel::Unreliable, &payload, now),
+            SequenceNumber(0)
+        );
+        assert_eq!(
+            channels.outgoing(Channel::Unreliable, &payload, now),
+            SequenceNumber(1)
+        );
+        assert_eq!(
+            channels.outgoing(Channel::ReliableOrdered, &payload, now),
+            SequenceNumber(0)
+        );
+        assert_eq!(
+            channels.outgoing(Channel::UnreliableSequenced, &payload, now),
+            SequenceNumber(0)
+        );
+        assert_eq!(channels.reliable_tx().unacked(), 1);
+    }
+}
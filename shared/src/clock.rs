@@ -0,0 +1,248 @@
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Number of exchanges the estimate is based on
+const MAX_SAMPLES: usize = 16;
+
+/// Exchanges done in quick succession after connecting, to get a first estimate
+const INITIAL_SAMPLES: usize = 4;
+
+/// Interval between exchanges until `INITIAL_SAMPLES` have been taken
+pub const INITIAL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Interval between exchanges after that, which is enough to follow the clocks drifting apart
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Samples with a round trip this many times longer than the shortest one are not used for
+/// estimating drift, as queueing likely made them asymmetric
+const MAX_DELAY_RATIO: i64 = 2;
+
+/// One exchange of `Message::TimeRequest` and `Message::TimeResponse`
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// When the response arrived, by our clock
+    received: DateTime<Utc>,
+    /// Server clock minus ours, in nanoseconds
+    offset: i64,
+    /// Round trip minus the time the server spent, in nanoseconds
+    delay: i64,
+}
+
+/// Estimates the offset and drift of the server's clock relative to ours, NTP style.
+///
+/// Each exchange yields four timestamps: the request leaving the client (t0) and arriving at
+/// the server (t1), and the response leaving the server (t2) and arriving at the client (t3).
+/// Assuming both directions take equally long, the server's clock is ahead by
+/// `((t1 - t0) + (t2 - t3)) / 2`, and the network took `(t3 - t0) - (t2 - t1)`. The sample with
+/// the shortest round trip is the most trustworthy, since asymmetric queueing can only add
+/// delay, and drift is the slope of the offset over the samples that were nearly as fast.
+#[derive(Debug, Default, Clone)]
+pub struct ClockSync {
+    samples: VecDeque<Sample>,
+    requested: Option<Instant>,
+    taken: usize,
+}
+
+impl ClockSync {
+    pub fn new() -> ClockSync {
+        Default::default()
+    }
+
+    /// Whether it is time for another exchange
+    pub fn due(&self, now: Instant) -> bool {
+        let interval = if self.taken < INITIAL_SAMPLES {
+            INITIAL_INTERVAL
+        } else {
+            SYNC_INTERVAL
+        };
+        self.requested
+            .is_none_or(|requested| now.duration_since(requested) >= interval)
+    }
+
+    pub fn on_request(&mut self, now: Instant) {
+        self.requested = Some(now);
+    }
+
+    /// Adds the sample from an exchange, see `ClockSync` for the meaning of the timestamps
+    pub fn on_response(
+        &mut self,
+        client_sent: DateTime<Utc>,
+        server_received: DateTime<Utc>,
+        server_sent: DateTime<Utc>,
+        client_received: DateTime<Utc>,
+    ) {
+        let nanos = |a: DateTime<Utc>, b: DateTime<Utc>| {
+            a.signed_duration_since(b).num_nanoseconds().unwrap_or(0)
+        };
+        let outbound = nanos(server_received, client_sent);
+        let inbound = nanos(server_sent, client_received);
+        let delay = nanos(client_received, client_sent) - nanos(server_sent, server_received);
+        if delay < 0 {
+            // the server's clock went backwards, or timestamps lost their precision
+            return;
+        }
+        self.taken += 1;
+        self.samples.push_back(Sample {
+            received: client_received,
+            offset: (outbound + inbound) / 2,
+            delay,
+        });
+        if self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Whether an exchange has completed, so that `server_time` is more than a guess
+    pub fn is_synced(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    fn best(&self) -> Option<&Sample> {
+        self.samples.iter().min_by_key(|sample| sample.delay)
+    }
+
+    /// Shortest round trip seen recently, not counting the time the server spent
+    pub fn delay(&self) -> Option<Duration> {
+        self.best()
+            .map(|sample| Duration::from_nanos(sample.delay as u64))
+    }
+
+    /// Rate at which the server's clock gains on ours, in seconds per second
+    pub fn drift(&self) -> f64 {
+        let best = match self.best() {
+            Some(best) => best,
+            None => return 0.0,
+        };
+        let fast: Vec<(f64, f64)> = self
+            .samples
+            .iter()
+            .filter(|sample| sample.delay <= best.delay.max(1) * MAX_DELAY_RATIO)
+            .map(|sample| {
+                let x = sample
+                    .received
+                    .signed_duration_since(best.received)
+                    .num_nanoseconds()
+                    .unwrap_or(0);
+                (x as f64, sample.offset as f64)
+            })
+            .collect();
+        if fast.len() < 2 {
+            return 0.0;
+        }
+        // least squares fit of the offset over time
+        let n = fast.len() as f64;
+        let mean_x = fast.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = fast.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let covariance: f64 = fast.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+        let variance: f64 = fast.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        if variance == 0.0 {
+            0.0
+        } else {
+            covariance / variance
+        }
+    }
+
+    /// Server clock minus ours at `now`, once an exchange has completed
+    pub fn offset(&self, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        let best = self.best()?;
+        let elapsed = now
+            .signed_duration_since(best.received)
+            .num_nanoseconds()
+            .unwrap_or(0);
+        let offset = best.offset as f64 + self.drift() * elapsed as f64;
+        Some(chrono::Duration::nanoseconds(offset as i64))
+    }
+
+    /// Estimate of the server's clock at `now` by ours, which is `now` until an exchange has
+    /// completed
+    pub fn server_time(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self.offset(now) {
+            Some(offset) => now + offset,
+            None => now,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs exchanges with a server whose clock is `offset` ahead and gains `drift` seconds per
+    /// second, every `interval` with the given one-way delays
+    fn simulate(
+        sync: &mut ClockSync,
+        start: DateTime<Utc>,
+        offset: chrono::Duration,
+        drift: f64,
+        interval: chrono::Duration,
+        delays: &[(i64, i64)],
+    ) {
+        let server_clock = |t: DateTime<Utc>| {
+            let elapsed = t.signed_duration_since(start).num_nanoseconds().unwrap() as f64;
+            t + offset + chrono::Duration::nanoseconds((elapsed * drift) as i64)
+        };
+        let ms = chrono::Duration::milliseconds;
+        for (i, &(outbound, inbound)) in delays.iter().enumerate() {
+            let t0 = start + interval * i as i32;
+            let t1 = server_clock(t0 + ms(outbound));
+            let t2 = server_clock(t0 + ms(outbound + 1));
+            let t3 = t0 + ms(outbound + 1 + inbound);
+            sync.on_response(t0, t1, t2, t3);
+        }
+    }
+
+    #[test]
+    fn test_offset() {
+        let mut sync = ClockSync::new();
+        let start = Utc::now();
+        assert_eq!(sync.server_time(start), start);
+        assert!(!sync.is_synced());
+        // one of the exchanges got stuck in a queue on the way back
+        simulate(
+            &mut sync,
+            start,
+            chrono::Duration::seconds(5),
+            0.0,
+            chrono::Duration::seconds(1),
+            &[(20, 20), (20, 120), (21, 21), (20, 20)],
+        );
+        assert_eq!(sync.delay(), Some(Duration::from_millis(40)));
+        let offset = sync.offset(start).unwrap().num_milliseconds();
+        assert_eq!(offset, 5000);
+        assert!(sync.drift().abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut sync = ClockSync::new();
+        let start = Utc::now();
+        let drift = 100e-6;
+        simulate(
+            &mut sync,
+            start,
+            chrono::Duration::milliseconds(-300),
+            drift,
+            chrono::Duration::seconds(10),
+            &[(20, 20); 8],
+        );
+        assert!((sync.drift() - drift).abs() < 1e-6, "{}", sync.drift());
+        // an hour later, the clocks are 360ms further apart
+        let later = start + chrono::Duration::hours(1);
+        let offset = sync.offset(later).unwrap().num_milliseconds();
+        assert!((offset - 60).abs() <= 1, "{}", offset);
+    }
+
+    #[test]
+    fn test_due() {
+        let mut sync = ClockSync::new();
+        let now = Instant::now();
+        assert!(sync.due(now));
+        sync.on_request(now);
+        assert!(!sync.due(now));
+        assert!(sync.due(now + INITIAL_INTERVAL));
+        sync.taken = INITIAL_SAMPLES;
+        assert!(!sync.due(now + INITIAL_INTERVAL));
+        assert!(sync.due(now + SYNC_INTERVAL));
+    }
+}
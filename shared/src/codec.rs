@@ -0,0 +1,338 @@
+use crate::packet::MAX_MESSAGE_SIZE;
+use crate::proto::{Message, MessageKind};
+use bincode::Options;
+use serde_derive::{Deserialize, Serialize};
+use snafu::{ensure, Snafu};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Snafu)]
+pub enum CodecError {
+    #[snafu(display("unable to encode message as {}: {}", codec, source))]
+    Encode {
+        codec: CodecKind,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    #[snafu(display("unable to decode {} message: {}", codec, source))]
+    Decode {
+        codec: CodecKind,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    #[snafu(display("message of {} bytes exceeds the maximum of {}", size, max))]
+    PayloadTooLarge { size: usize, max: usize },
+    #[snafu(display("{:?} message of {} bytes exceeds the maximum of {}", kind, size, max))]
+    MessageTooLarge {
+        kind: MessageKind,
+        size: usize,
+        max: usize,
+    },
+}
+
+/// Default upper bound for the encoded size of a message, which most are far below
+pub const DEFAULT_MESSAGE_LIMIT: usize = 512;
+
+/// Upper bounds for the encoded size of incoming messages, by message type.
+///
+/// A payload larger than any limit is rejected before decoding it. Decoding can then allocate
+/// no more than the payload could hold, see `Bincode`, and the limit for the type of the decoded
+/// message is checked afterwards.
+#[derive(Debug, Clone)]
+pub struct DecodeLimits {
+    default: usize,
+    limits: HashMap<MessageKind, usize>,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> DecodeLimits {
+        DecodeLimits::new(DEFAULT_MESSAGE_LIMIT)
+            .with_limit(MessageKind::Handshake, 2048)
+            .with_limit(MessageKind::Refresh, MAX_MESSAGE_SIZE)
+    }
+}
+
+impl DecodeLimits {
+    /// Limits every message type to `default` bytes
+    pub fn new(default: usize) -> DecodeLimits {
+        DecodeLimits {
+            default,
+            limits: HashMap::new(),
+        }
+    }
+
+    /// Changes the limit for message types without a limit of their own
+    pub fn with_default(mut self, max: usize) -> DecodeLimits {
+        self.default = max;
+        self
+    }
+
+    pub fn with_limit(mut self, kind: MessageKind, max: usize) -> DecodeLimits {
+        self.limits.insert(kind, max);
+        self
+    }
+
+    pub fn limit(&self, kind: MessageKind) -> usize {
+        self.limits.get(&kind).cloned().unwrap_or(self.default)
+    }
+
+    /// Largest limit of any message type
+    pub fn max(&self) -> usize {
+        self.limits.values().cloned().fold(self.default, usize::max)
+    }
+}
+
+/// Turns messages into the payloads carried by chunks and back
+pub trait Codec {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, payload: &[u8]) -> Result<Message, CodecError>;
+
+    /// Decodes a message received from a peer, which may be trying to exhaust our memory
+    fn decode_limited(&self, payload: &[u8], limits: &DecodeLimits) -> Result<Message, CodecError> {
+        let size = payload.len();
+        let max = limits.max();
+        ensure!(size <= max, PayloadTooLarge { size, max });
+        let message = self.decode(payload)?;
+        let kind = message.kind();
+        let max = limits.limit(kind);
+        ensure!(size <= max, MessageTooLarge { kind, size, max });
+        Ok(message)
+    }
+}
+
+/// Compact binary encoding that only Rust peers using the same `Message` definition understand.
+/// Decoding never reads past the end of the payload, so a length prefix larger than the rest of
+/// the payload is an error rather than an allocation of that size.
+#[derive(Debug, Clone, Copy)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(message).map_err(|err| CodecError::Encode {
+            codec: CodecKind::Bincode,
+            source: err.into(),
+        })
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Message, CodecError> {
+        bincode_options(payload.len())
+            .deserialize(payload)
+            .map_err(|err| CodecError::Decode {
+                codec: CodecKind::Bincode,
+                source: err.into(),
+            })
+    }
+}
+
+/// Options matching those of `bincode::serialize`, with allocations while decoding limited to
+/// `limit` bytes
+pub(crate) fn bincode_options(limit: usize) -> impl bincode::Options {
+    bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit as u64)
+}
+
+/// MessagePack with named fields, which is self-describing while staying fairly compact
+#[derive(Debug, Clone, Copy)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(message).map_err(|err| CodecError::Encode {
+            codec: CodecKind::MessagePack,
+            source: err.into(),
+        })
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Message, CodecError> {
+        rmp_serde::from_slice(payload).map_err(|err| CodecError::Decode {
+            codec: CodecKind::MessagePack,
+            source: err.into(),
+        })
+    }
+}
+
+/// JSON, which is by far the largest but can be read in a packet capture and spoken by tools
+/// written in any language
+#[derive(Debug, Clone, Copy)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(message).map_err(|err| CodecError::Encode {
+            codec: CodecKind::Json,
+            source: err.into(),
+        })
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Message, CodecError> {
+        serde_json::from_slice(payload).map_err(|err| CodecError::Decode {
+            codec: CodecKind::Json,
+            source: err.into(),
+        })
+    }
+}
+
+/// Codec used for the messages of a connection.
+///
+/// The client proposes one in its handshake `Response` and the server names the one it picked
+/// in `Success`, which is the proposed one unless the server does not allow it. The handshake
+/// itself is always encoded with `Bincode`: the server switches codecs for the messages it sends
+/// after `Success` and the ones it receives after accepting the `Response`, the client for the
+/// messages it receives and sends after `Success`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CodecKind {
+    #[default]
+    Bincode,
+    MessagePack,
+    Json,
+}
+
+impl CodecKind {
+    pub const ALL: &'static [CodecKind] =
+        &[CodecKind::Bincode, CodecKind::MessagePack, CodecKind::Json];
+
+    /// Environment variable selecting the codec a client proposes, "bincode", "msgpack" or
+    /// "json"
+    pub const ENV_VAR: &'static str = "BASEPLATE_CODEC";
+
+    /// Reads the codec from `CodecKind::ENV_VAR`, falling back to the default when it is unset
+    pub fn from_env() -> Result<CodecKind, String> {
+        match std::env::var(CodecKind::ENV_VAR) {
+            Ok(value) => value.parse(),
+            Err(_) => Ok(Default::default()),
+        }
+    }
+
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            CodecKind::Bincode => &Bincode,
+            CodecKind::MessagePack => &MessagePack,
+            CodecKind::Json => &Json,
+        }
+    }
+}
+
+impl Codec for CodecKind {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        self.codec().encode(message)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Message, CodecError> {
+        self.codec().decode(payload)
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CodecKind, String> {
+        match s {
+            "bincode" => Ok(CodecKind::Bincode),
+            "msgpack" => Ok(CodecKind::MessagePack),
+            "json" => Ok(CodecKind::Json),
+            _ => Err(format!("unknown codec {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for CodecKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            CodecKind::Bincode => "bincode",
+            CodecKind::MessagePack => "msgpack",
+            CodecKind::Json => "json",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::HandshakeMessage;
+    use chrono::Utc;
+
+    #[test]
+    fn test_roundtrip() {
+        let messages = vec![
+            Message::Heartbeat,
+            Message::Move { dx: 1.5, dy: -2.0 },
+            Message::Refresh(vec![(0.0, 1.0), (2.5, 3.0)]),
+            Message::Handshake(HandshakeMessage::Success {
+                proof: [7; 32],
+                resume: [9; 32],
+                codec: CodecKind::Json,
+            }),
+            Message::TimeRequest(Utc::now()),
+        ];
+        for &kind in CodecKind::ALL {
+            for message in &messages {
+                let payload = kind.encode(message).unwrap();
+                let decoded = kind.decode(&payload).unwrap();
+                // Message has no PartialEq, its debug output is close enough
+                assert_eq!(
+                    format!("{:?}", decoded),
+                    format!("{:?}", message),
+                    "{}",
+                    kind
+                );
+            }
+            assert_eq!(kind.to_string().parse::<CodecKind>(), Ok(kind));
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = DecodeLimits::new(16).with_limit(MessageKind::Refresh, 64);
+        assert_eq!(limits.max(), 64);
+        let small = Message::Refresh(vec![(1.0, 2.0)]);
+        let large = Message::Refresh(vec![(1.0, 2.0); 16]);
+        for &kind in CodecKind::ALL {
+            assert!(kind
+                .decode_limited(&kind.encode(&small).unwrap(), &limits)
+                .is_ok());
+            match kind.decode_limited(&kind.encode(&large).unwrap(), &limits) {
+                Err(CodecError::PayloadTooLarge { max: 64, .. }) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+
+        let limits = DecodeLimits::new(8).with_limit(MessageKind::Refresh, 64);
+        let payload = Bincode.encode(&Message::Move { dx: 1.0, dy: 2.0 }).unwrap();
+        match Bincode.decode_limited(&payload, &limits) {
+            Err(CodecError::MessageTooLarge {
+                kind: MessageKind::Move,
+                size: 12,
+                max: 8,
+            }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_length_bomb() {
+        // a refresh claiming 2^60 positions in a 16 byte payload
+        let mut payload = Bincode.encode(&Message::Refresh(vec![])).unwrap();
+        payload.truncate(4);
+        payload.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        payload.extend_from_slice(&[0; 4]);
+        match Bincode.decode_limited(&payload, &DecodeLimits::default()) {
+            Err(CodecError::Decode { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_json_is_readable() {
+        let payload = Json.encode(&Message::Move { dx: 1.0, dy: 0.5 }).unwrap();
+        assert_eq!(payload, br#"{"Move":{"dx":1.0,"dy":0.5}}"#.to_vec());
+        match Json.decode(b"{\"Move\":") {
+            Err(CodecError::Decode {
+                codec: CodecKind::Json,
+                ..
+            }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use hmac::{Hmac, Mac, NewMac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+
+/// What a client presents to prove who it is, sent encrypted during the handshake
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Credentials {
+    /// Token shared with the server, which never leaves the client: it is replaced by a
+    /// `TokenProof` before sending, see `Credentials::bind`
+    Token {
+        username: String,
+        token: String,
+    },
+    /// HMAC of a handshake transcript keyed with the token, which is of no use in any other
+    /// handshake
+    TokenProof {
+        username: String,
+        proof: [u8; 32],
+    },
+    Ticket(Ticket),
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::Token { username, .. } => fmt
+                .debug_struct("Token")
+                .field("username", username)
+                .finish(),
+            Credentials::TokenProof { username, .. } => fmt
+                .debug_struct("TokenProof")
+                .field("username", username)
+                .finish(),
+            Credentials::Ticket(ticket) => fmt.debug_tuple("Ticket").field(ticket).finish(),
+        }
+    }
+}
+
+impl Credentials {
+    /// Username the client claims, which is only trustworthy once authenticated
+    pub fn username(&self) -> &str {
+        match self {
+            Credentials::Token { username, .. } | Credentials::TokenProof { username, .. } => {
+                username
+            }
+            Credentials::Ticket(ticket) => &ticket.username,
+        }
+    }
+
+    /// Binds the credentials to the handshake with `transcript`, replacing a token with a
+    /// proof that the client holds it
+    pub fn bind(&self, transcript: &[u8]) -> Credentials {
+        match self {
+            Credentials::Token { username, token } => Credentials::TokenProof {
+                username: username.clone(),
+                proof: token_proof(token, username, transcript),
+            },
+            other => other.clone(),
+        }
+    }
+}
+
+/// Proof that the holder of `token` for `username` took part in the handshake with `transcript`
+pub fn token_proof(token: &str, username: &str, transcript: &[u8]) -> [u8; 32] {
+    let mut proof = [0; 32];
+    proof.copy_from_slice(
+        &token_mac(token, username, transcript)
+            .finalize()
+            .into_bytes(),
+    );
+    proof
+}
+
+/// Checks a proof made by `token_proof`, in constant time
+pub fn verify_token_proof(token: &str, username: &str, transcript: &[u8], proof: &[u8]) -> bool {
+    token_mac(token, username, transcript).verify(proof).is_ok()
+}
+
+fn token_mac(token: &str, username: &str, transcript: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(token.as_bytes()).expect("HMAC accepts any key");
+    mac.update(transcript);
+    mac.update(username.as_bytes());
+    mac
+}
+
+/// Proof of identity issued by a login service that shares a secret with the game server.
+///
+/// The text form is `username:expires:signature`, with the expiry in seconds since the Unix
+/// epoch and the signature in hex.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Ticket {
+    pub username: String,
+    /// Seconds since the Unix epoch
+    pub expires: u64,
+    pub signature: [u8; 32],
+}
+
+impl Ticket {
+    pub fn sign(secret: &[u8], username: &str, expires: u64) -> Ticket {
+        let mut signature = [0; 32];
+        signature.copy_from_slice(&mac(secret, username, expires).finalize().into_bytes());
+        Ticket {
+            username: username.to_owned(),
+            expires,
+            signature,
+        }
+    }
+
+    /// Checks the signature, in constant time, and that the ticket has not expired
+    pub fn verify(&self, secret: &[u8], now: u64) -> bool {
+        let valid = mac(secret, &self.username, self.expires)
+            .verify(&self.signature)
+            .is_ok();
+        valid && now < self.expires
+    }
+}
+
+fn mac(secret: &[u8], username: &str, expires: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts any key");
+    mac.update(&expires.to_be_bytes());
+    mac.update(username.as_bytes());
+    mac
+}
+
+impl fmt::Display for Ticket {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}:{}:", self.username, self.expires)?;
+        for byte in &self.signature {
+            write!(fmt, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Ticket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Ticket, String> {
+        let invalid = || format!("invalid ticket {:?}", s);
+        // usernames may contain colons, the other fields may not
+        let mut parts = s.rsplitn(3, ':');
+        let hex = parts.next().ok_or_else(invalid)?;
+        let expires = parts.next().ok_or_else(invalid)?;
+        let username = parts.next().ok_or_else(invalid)?;
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut signature = [0; 32];
+        for (i, byte) in signature.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Ticket {
+            username: username.to_owned(),
+            expires: expires.parse().map_err(|_| invalid())?,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket() {
+        let ticket = Ticket::sign(b"secret", "player:one", 1000);
+        assert!(ticket.verify(b"secret", 999));
+        assert!(!ticket.verify(b"secret", 1000));
+        assert!(!ticket.verify(b"other secret", 999));
+
+        let mut forged = ticket.clone();
+        forged.username = "admin".to_owned();
+        assert!(!forged.verify(b"secret", 999));
+
+        assert_eq!(ticket.to_string().parse::<Ticket>().unwrap(), ticket);
+        assert!("player:1000:abcd".parse::<Ticket>().is_err());
+    }
+
+    #[test]
+    fn test_debug_hides_token() {
+        let credentials = Credentials::Token {
+            username: "player".to_owned(),
+            token: "hunter2".to_owned(),
+        };
+        assert!(!format!("{:?}", credentials).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_token_proof() {
+        let credentials = Credentials::Token {
+            username: "player".to_owned(),
+            token: "hunter2".to_owned(),
+        };
+        let proof = match credentials.bind(b"transcript") {
+            Credentials::TokenProof { username, proof } => {
+                assert_eq!(username, "player");
+                proof
+            }
+            other => panic!("unexpected credentials {:?}", other),
+        };
+        assert!(verify_token_proof(
+            "hunter2",
+            "player",
+            b"transcript",
+            &proof
+        ));
+        assert!(!verify_token_proof(
+            "hunter2",
+            "player",
+            b"other transcript",
+            &proof
+        ));
+        assert!(!verify_token_proof(
+            "hunter3",
+            "player",
+            b"transcript",
+            &proof
+        ));
+        assert!(!verify_token_proof(
+            "hunter2",
+            "admin",
+            b"transcript",
+            &proof
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::packet::{PacketError, SequenceNumber};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Size of a ChaCha20-Poly1305 key
+pub const KEY_SIZE: usize = 32;
+
+/// Size of the authentication tag appended to every encrypted message
+pub const TAG_SIZE: usize = 16;
+
+pub type SessionKey = [u8; KEY_SIZE];
+
+/// Authenticated encryption of packets with ChaCha20-Poly1305.
+///
+/// Each direction has its own key, so that both ends can use their packet sequence numbers as
+/// nonces without ever reusing a nonce under the same key. Sequence numbers wrap around after
+/// 2^32 packets, so `encrypt` refuses to encrypt any more than that and the session has to be
+/// rekeyed.
+pub struct Cipher {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    /// Number of messages encrypted so far, each under a different sequence number
+    encrypted: AtomicU64,
+}
+
+/// Number of distinct nonces `Cipher::encrypt` can derive from sequence numbers
+const MAX_ENCRYPTIONS: u64 = 1 << 32;
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("Cipher { .. }")
+    }
+}
+
+fn nonce(sequence: SequenceNumber) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[8..].copy_from_slice(&sequence.0.to_be_bytes());
+    Nonce::from(nonce)
+}
+
+/// Encrypts a single message with a key that is used for nothing else, which makes the fixed
+/// nonce safe
+pub fn seal(key: &SessionKey, message: &[u8]) -> Result<Vec<u8>, PacketError> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .encrypt(&nonce(SequenceNumber(0)), message)
+        .map_err(|_| PacketError::EncryptionFailed)
+}
+
+/// Decrypts a message encrypted with `seal`
+pub fn open(key: &SessionKey, ciphertext: &[u8]) -> Result<Vec<u8>, PacketError> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .decrypt(&nonce(SequenceNumber(0)), ciphertext)
+        .map_err(|_| PacketError::AuthenticationFailed)
+}
+
+impl Cipher {
+    pub fn new(send_key: &SessionKey, recv_key: &SessionKey) -> Cipher {
+        Cipher {
+            send: ChaCha20Poly1305::new(&Key::from(*send_key)),
+            recv: ChaCha20Poly1305::new(&Key::from(*recv_key)),
+            encrypted: AtomicU64::new(0),
+        }
+    }
+
+    /// Encrypts the message of outgoing packet `sequence`, authenticating `header` along with it.
+    ///
+    /// Fails with `PacketError::NoncesExhausted` once sequence numbers would wrap around.
+    pub fn encrypt(
+        &self,
+        sequence: SequenceNumber,
+        header: &[u8],
+        message: &[u8],
+    ) -> Result<Vec<u8>, PacketError> {
+        if self.encrypted.fetch_add(1, Ordering::Relaxed) >= MAX_ENCRYPTIONS {
+            return Err(PacketError::NoncesExhausted);
+        }
+        self.send
+            .encrypt(
+                &nonce(sequence),
+                Payload {
+                    msg: message,
+                    aad: header,
+                },
+            )
+            .map_err(|_| PacketError::EncryptionFailed)
+    }
+
+    /// Decrypts the message of incoming packet `sequence`, failing if either the message or
+    /// `header` has been tampered with
+    pub fn decrypt(
+        &self,
+        sequence: SequenceNumber,
+        header: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, PacketError> {
+        if ciphertext.len() < TAG_SIZE {
+            return Err(PacketError::DecryptionFailed);
+        }
+        self.recv
+            .decrypt(
+                &nonce(sequence),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| PacketError::AuthenticationFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let client = Cipher::new(&[1; KEY_SIZE], &[2; KEY_SIZE]);
+        let server = Cipher::new(&[2; KEY_SIZE], &[1; KEY_SIZE]);
+        let sequence = SequenceNumber(7);
+        let ciphertext = client.encrypt(sequence, b"header", b"MOVE").unwrap();
+        assert_eq!(ciphertext.len(), 4 + TAG_SIZE);
+        assert_eq!(
+            server.decrypt(sequence, b"header", &ciphertext).unwrap(),
+            b"MOVE"
+        );
+
+        // wrong header, wrong nonce, wrong direction
+        for result in [
+            server.decrypt(sequence, b"HEADER", &ciphertext),
+            server.decrypt(sequence.next(), b"header", &ciphertext),
+            client.decrypt(sequence, b"header", &ciphertext),
+        ] {
+            match result {
+                Err(PacketError::AuthenticationFailed) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        match server.decrypt(sequence, b"header", &ciphertext[..TAG_SIZE - 1]) {
+            Err(PacketError::DecryptionFailed) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_nonces_exhausted() {
+        let client = Cipher::new(&[1; KEY_SIZE], &[2; KEY_SIZE]);
+        client
+            .encrypted
+            .store(MAX_ENCRYPTIONS - 1, Ordering::Relaxed);
+        assert!(client.encrypt(SequenceNumber(0), b"", b"last").is_ok());
+        for _ in 0..2 {
+            match client.encrypt(SequenceNumber(1), b"", b"reused") {
+                Err(PacketError::NoncesExhausted) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
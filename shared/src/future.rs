@@ -1,22 +1,115 @@
 use crate::Result;
+use futures_timer::Delay;
 use log::warn;
+use std::error::Error;
 use std::future::Future;
+use std::time::Duration;
 
-pub async fn retry<F, T>(times: usize, f: fn() -> F) -> Result<T>
+/// Delays between the attempts of `retry`, doubling from `initial` up to `max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Delay after the given number of failed attempts
+    pub fn delay(&self, failures: u32) -> Duration {
+        // saturates long before the shift could overflow
+        let factor = 1u32 << failures.saturating_sub(1).min(16);
+        self.initial
+            .checked_mul(factor)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+/// Calls `f` until it succeeds, at most `times` times, waiting longer after each failure
+pub async fn retry<F, Fut, T>(times: usize, backoff: Backoff, f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    retry_unless(times, backoff, |_| false, f).await
+}
+
+/// Like `retry`, but gives up right away on an error `permanent` holds for, as trying again
+/// would fail the same way
+pub async fn retry_unless<F, Fut, T, P>(
+    times: usize,
+    backoff: Backoff,
+    permanent: P,
+    mut f: F,
+) -> Result<T>
 where
-    F: Future<Output = Result<T>>,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+    P: Fn(&(dyn Error + Send + Sync + 'static)) -> bool,
 {
     let mut counter = 0;
     loop {
         match f().await {
             Err(err) => {
                 counter += 1;
-                warn!("attempt {}/{} failed", counter, times);
-                if counter == times {
+                warn!("attempt {}/{} failed: {}", counter, times, err);
+                if counter == times || permanent(&*err) {
                     break Err(err);
                 }
+                Delay::new(backoff.delay(counter as u32)).await;
             }
             Ok(res) => break Ok(res),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future;
+    use std::task::{Context, Poll, Waker};
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let delays: Vec<_> = (1..6).map(|failures| backoff.delay(failures)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000]
+                .iter()
+                .map(|&millis| Duration::from_millis(millis))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(backoff.delay(u32::MAX), backoff.max);
+    }
+
+    #[test]
+    fn test_retry_unless() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(1),
+        };
+        let mut attempts = 0;
+        let retried = retry_unless(
+            5,
+            backoff,
+            |err| err.to_string() == "refused",
+            || {
+                attempts += 1;
+                future::ready(Err::<(), _>("refused".into()))
+            },
+        );
+        // finishes without ever waiting on the backoff
+        let mut retried = Box::pin(retried);
+        match retried
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+        {
+            Poll::Ready(result) => assert!(result.is_err()),
+            Poll::Pending => panic!("retried a permanent error"),
+        }
+        drop(retried);
+        assert_eq!(attempts, 1);
+    }
+}
@@ -1,15 +1,357 @@
+use crate::codec::{self, CodecKind};
+use crate::credentials::Credentials;
+use crate::crypto::{self, Cipher, SessionKey, KEY_SIZE};
+use crate::packet::ConnectionId;
+use crate::proto::MessageKind;
+use bincode::Options;
+use hkdf::Hkdf;
+use rand_core::OsRng;
 use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use x25519_dalek::StaticSecret;
 
-#[derive(Debug, PartialOrd, PartialEq, Eq, Clone)]
+/// X25519 public key as sent on the wire
+pub type PublicKey = [u8; 32];
+
+/// Value proving that its sender derived the same session keys
+pub type Proof = [u8; 32];
+
+/// Token the server hands out to an address before allocating a session for it, see
+/// `Message::Connect`
+pub type Cookie = [u8; 32];
+
+/// Token the server hands out on `Success`, which lets the client pick up its session again
+/// after reconnecting
+pub type ResumeToken = [u8; 32];
+
+/// Context string mixed into the key derivation, so keys are never shared with other protocols
+const KEY_INFO: &[u8] = b"baseplate session keys";
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum HandshakeState {
     Disconnected,
-    Negotiating { nonce: u32 },
+    Negotiating { exchange: KeyExchange },
     Connected,
 }
 
+impl HandshakeState {
+    /// Messages the server accepts from a client in this state. Anything else is rejected,
+    /// in particular gameplay messages before the handshake has completed.
+    pub fn allowed(&self) -> &'static [MessageKind] {
+        match self {
+            HandshakeState::Disconnected => &[MessageKind::Connect],
+            HandshakeState::Negotiating { .. } => &[
+                MessageKind::Connect,
+                MessageKind::Handshake,
+                MessageKind::Disconnect,
+            ],
+            HandshakeState::Connected => &[
+                MessageKind::Connect,
+                MessageKind::Disconnect,
+                MessageKind::Heartbeat,
+                MessageKind::Move,
+                MessageKind::PathResponse,
+                MessageKind::TimeRequest,
+            ],
+        }
+    }
+
+    pub fn allows(&self, kind: MessageKind) -> bool {
+        self.allowed().contains(&kind)
+    }
+}
+
+/// Handshake flow:
+///
+/// 1. the client sends `Message::Connect` with a valid cookie
+/// 2. the server answers with its public key in a `Challenge`, along with the connection ID the
+///    client puts in the header of its packets from now on
+/// 3. the client derives the session keys and answers with its public key, a proof and its
+///    credentials, encrypted with a key of their own. A client reconnecting after losing its
+///    session adds the resumption token it got last time. It also proposes the codec for the
+///    rest of the connection.
+/// 4. the server derives the session keys, checks the proof and answers with `Success` carrying
+///    its own proof, a fresh resumption token and the codec it picked, already encrypted
+///
+/// Both sides only consider the connection established after checking the other side's proof.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum HandshakeMessage {
-    Challenge(u32),
-    Success,
-    Failure,
+    Challenge {
+        public_key: PublicKey,
+        connection_id: ConnectionId,
+    },
+    Response {
+        public_key: PublicKey,
+        proof: Proof,
+        /// `Credentials` and resumption token, see `SessionKeys::seal_credentials`
+        credentials: Vec<u8>,
+        codec: CodecKind,
+    },
+    Success {
+        proof: Proof,
+        resume: ResumeToken,
+        codec: CodecKind,
+    },
+    Failure(FailureReason),
+}
+
+/// Why the server refused a connection
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    /// The server has no room for more sessions
+    ServerFull,
+    /// The server speaks another protocol version. Reserved: the server never sends it, as a
+    /// client with another version could not decode it. Such clients are turned away with
+    /// `packet::version_rejection` before any session exists.
+    VersionMismatch {
+        server: u8,
+    },
+    Banned,
+    BadCredentials,
+    /// The key proof did not match, so the two sides derived different keys
+    BadChallenge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Ephemeral Diffie-Hellman key pair of one side of the handshake, discarded once the session
+/// keys have been derived
+#[derive(Clone)]
+pub struct KeyExchange {
+    secret: StaticSecret,
+    public_key: PublicKey,
+}
+
+impl fmt::Debug for KeyExchange {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("KeyExchange")
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+impl PartialEq for KeyExchange {
+    fn eq(&self, other: &KeyExchange) -> bool {
+        self.public_key == other.public_key
+    }
+}
+
+impl Eq for KeyExchange {}
+
+impl Default for KeyExchange {
+    fn default() -> KeyExchange {
+        KeyExchange::new()
+    }
+}
+
+impl KeyExchange {
+    pub fn new() -> KeyExchange {
+        let secret = StaticSecret::new(OsRng);
+        let public_key = *x25519_dalek::PublicKey::from(&secret).as_bytes();
+        KeyExchange { secret, public_key }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Derives the session keys shared with the owner of `peer`
+    pub fn derive(&self, role: Role, peer: &PublicKey) -> SessionKeys {
+        let shared = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(*peer));
+        // Both public keys go into the salt, in the same order on both sides
+        let (client, server) = match role {
+            Role::Client => (&self.public_key, peer),
+            Role::Server => (peer, &self.public_key),
+        };
+        let salt = [&client[..], &server[..]].concat();
+        let mut okm = [0u8; 5 * KEY_SIZE];
+        Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+            .expand(KEY_INFO, &mut okm)
+            .expect("output length is valid for SHA-256");
+        let mut keys = SessionKeys {
+            role,
+            transcript: salt,
+            client_key: [0; KEY_SIZE],
+            server_key: [0; KEY_SIZE],
+            client_proof: [0; KEY_SIZE],
+            server_proof: [0; KEY_SIZE],
+            credentials_key: [0; KEY_SIZE],
+        };
+        keys.client_key.copy_from_slice(&okm[..KEY_SIZE]);
+        keys.server_key
+            .copy_from_slice(&okm[KEY_SIZE..2 * KEY_SIZE]);
+        keys.client_proof
+            .copy_from_slice(&okm[2 * KEY_SIZE..3 * KEY_SIZE]);
+        keys.server_proof
+            .copy_from_slice(&okm[3 * KEY_SIZE..4 * KEY_SIZE]);
+        keys.credentials_key.copy_from_slice(&okm[4 * KEY_SIZE..]);
+        keys
+    }
+}
+
+/// Keys derived by the handshake: one encryption key per direction, and the proofs both sides
+/// exchange to show they derived the same keys
+pub struct SessionKeys {
+    role: Role,
+    client_key: SessionKey,
+    server_key: SessionKey,
+    client_proof: Proof,
+    server_proof: Proof,
+    credentials_key: SessionKey,
+    /// Public keys of the client and the server, which credentials are bound to
+    transcript: Vec<u8>,
+}
+
+impl SessionKeys {
+    /// Cipher for our side of the session
+    pub fn cipher(&self) -> Cipher {
+        match self.role {
+            Role::Client => Cipher::new(&self.client_key, &self.server_key),
+            Role::Server => Cipher::new(&self.server_key, &self.client_key),
+        }
+    }
+
+    /// Proof to send to the other side
+    pub fn proof(&self) -> Proof {
+        match self.role {
+            Role::Client => self.client_proof,
+            Role::Server => self.server_proof,
+        }
+    }
+
+    /// Handshake transcript the client's credentials are bound to, see `Credentials::bind`
+    pub fn transcript(&self) -> &[u8] {
+        &self.transcript
+    }
+
+    /// Binds the client's credentials to this handshake and encrypts them along with the
+    /// resumption token, as they are sent before the session is encrypted
+    pub fn seal_credentials(
+        &self,
+        credentials: &Credentials,
+        resume: Option<ResumeToken>,
+    ) -> Vec<u8> {
+        let credentials = credentials.bind(&self.transcript);
+        let plaintext =
+            bincode::serialize(&(credentials, resume)).expect("credentials are serializable");
+        crypto::seal(&self.credentials_key, &plaintext).expect("credentials fit in a message")
+    }
+
+    /// Decrypts the client's credentials and resumption token, or `None` if they were tampered
+    /// with
+    pub fn open_credentials(&self, sealed: &[u8]) -> Option<(Credentials, Option<ResumeToken>)> {
+        let plaintext = crypto::open(&self.credentials_key, sealed).ok()?;
+        codec::bincode_options(plaintext.len())
+            .deserialize(&plaintext)
+            .ok()
+    }
+
+    /// Checks the proof received from the other side, in constant time
+    pub fn verify(&self, proof: &Proof) -> bool {
+        let expected = match self.role {
+            Role::Client => &self.server_proof,
+            Role::Server => &self.client_proof,
+        };
+        expected
+            .iter()
+            .zip(proof.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::SequenceNumber;
+
+    #[test]
+    fn test_key_exchange() {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_keys = client.derive(Role::Client, &server.public_key());
+        let server_keys = server.derive(Role::Server, &client.public_key());
+
+        assert!(server_keys.verify(&client_keys.proof()));
+        assert!(client_keys.verify(&server_keys.proof()));
+        // a proof is only valid in one direction
+        assert!(!client_keys.verify(&client_keys.proof()));
+
+        let sequence = SequenceNumber(1);
+        let ciphertext = client_keys
+            .cipher()
+            .encrypt(sequence, b"", b"MOVE")
+            .unwrap();
+        assert_eq!(
+            server_keys
+                .cipher()
+                .decrypt(sequence, b"", &ciphertext)
+                .unwrap(),
+            b"MOVE"
+        );
+    }
+
+    #[test]
+    fn test_credentials() {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_keys = client.derive(Role::Client, &server.public_key());
+        let server_keys = server.derive(Role::Server, &client.public_key());
+        let credentials = Credentials::Token {
+            username: "player".to_owned(),
+            token: "hunter2".to_owned(),
+        };
+        let mut sealed = client_keys.seal_credentials(&credentials, Some([7; 32]));
+        assert_eq!(client_keys.transcript(), server_keys.transcript());
+        assert_eq!(
+            server_keys.open_credentials(&sealed),
+            Some((credentials.bind(server_keys.transcript()), Some([7; 32])))
+        );
+        sealed[0] ^= 1;
+        assert_eq!(server_keys.open_credentials(&sealed), None);
+    }
+
+    #[test]
+    fn test_allowed_messages() {
+        let negotiating = HandshakeState::Negotiating {
+            exchange: KeyExchange::new(),
+        };
+        for state in &[HandshakeState::Disconnected, negotiating.clone()] {
+            assert!(state.allows(MessageKind::Connect));
+            assert!(!state.allows(MessageKind::Move));
+            assert!(!state.allows(MessageKind::Heartbeat));
+        }
+        assert!(!HandshakeState::Disconnected.allows(MessageKind::Handshake));
+        assert!(negotiating.allows(MessageKind::Handshake));
+        assert!(HandshakeState::Connected.allows(MessageKind::Move));
+        assert!(!HandshakeState::Connected.allows(MessageKind::Handshake));
+        // only ever sent by the server
+        for state in &[
+            HandshakeState::Disconnected,
+            negotiating,
+            HandshakeState::Connected,
+        ] {
+            assert!(!state.allows(MessageKind::Refresh));
+            assert!(!state.allows(MessageKind::Cookie));
+            assert!(!state.allows(MessageKind::PathChallenge));
+            assert!(!state.allows(MessageKind::TimeResponse));
+        }
+    }
+
+    #[test]
+    fn test_wrong_peer() {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let attacker = KeyExchange::new();
+        let client_keys = client.derive(Role::Client, &attacker.public_key());
+        let server_keys = server.derive(Role::Server, &client.public_key());
+        assert!(!server_keys.verify(&client_keys.proof()));
+    }
 }
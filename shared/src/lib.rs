@@ -1,11 +1,21 @@
 #![feature(trace_macros)]
 
+pub mod ack;
+pub mod channel;
+pub mod clock;
+pub mod codec;
+pub mod credentials;
+pub mod crypto;
 pub mod future;
 pub mod handshake;
 pub mod logging;
+pub mod outbox;
 pub mod packet;
 pub mod proto;
+pub mod reliable;
 pub mod state;
+pub mod stats;
+pub mod window;
 
 use bytes::Bytes;
 use pretty_hex::PrettyHex;
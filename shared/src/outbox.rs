@@ -0,0 +1,116 @@
+use crate::channel::Channel;
+use crate::packet::{self, Chunk, Fragment, PacketError, SequenceNumber, MAX_PAYLOAD_SIZE};
+use bytes::Bytes;
+use std::collections::VecDeque;
+
+/// Queue of outgoing messages for a single connection.
+///
+/// Messages are queued as they are sent and packed into as few packets as possible when the
+/// connection is flushed, which normally happens once per tick.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    queue: VecDeque<Chunk>,
+}
+
+impl Outbox {
+    pub fn new() -> Outbox {
+        Default::default()
+    }
+
+    /// Queues a message, splitting it into fragments if it does not fit into a single packet
+    pub fn push(
+        &mut self,
+        channel: Channel,
+        channel_sequence: SequenceNumber,
+        payload: Bytes,
+    ) -> Result<(), PacketError> {
+        let fragments = packet::fragment(&payload)?;
+        let count = fragments.len() as u8;
+        for (index, payload) in fragments.into_iter().enumerate() {
+            self.queue.push_back(Chunk {
+                channel,
+                channel_sequence,
+                fragment: Fragment {
+                    index: index as u8,
+                    count,
+                },
+                payload,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Empties the queue, packing chunks in order into payloads of at most `MAX_PAYLOAD_SIZE`
+    /// bytes. Every element of the returned vector is the contents of one packet.
+    pub fn drain(&mut self) -> Vec<Vec<Chunk>> {
+        let mut packets = vec![];
+        let mut current: Vec<Chunk> = vec![];
+        let mut size = 0;
+        for chunk in self.queue.drain(..) {
+            if !current.is_empty() && size + chunk.encoded_len() > MAX_PAYLOAD_SIZE {
+                packets.push(current);
+                current = vec![];
+                size = 0;
+            }
+            size += chunk.encoded_len();
+            current.push(chunk);
+        }
+        if !current.is_empty() {
+            packets.push(current);
+        }
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{CHUNK_HEADER_SIZE, MAX_FRAGMENT_SIZE};
+
+    #[test]
+    fn test_coalesce() {
+        let mut outbox = Outbox::new();
+        for seq in 0..4 {
+            outbox
+                .push(
+                    Channel::Unreliable,
+                    SequenceNumber(seq),
+                    Bytes::from_static(b"move"),
+                )
+                .unwrap();
+        }
+        let packets = outbox.drain();
+        assert_eq!(packets.len(), 1);
+        let sequences: Vec<_> = packets[0].iter().map(|c| c.channel_sequence).collect();
+        assert_eq!(sequences, (0..4).map(SequenceNumber).collect::<Vec<_>>());
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn test_split_across_packets() {
+        let mut outbox = Outbox::new();
+        let large = Bytes::from(vec![0u8; MAX_FRAGMENT_SIZE * 2]);
+        outbox
+            .push(Channel::ReliableOrdered, SequenceNumber(0), large)
+            .unwrap();
+        outbox
+            .push(
+                Channel::Unreliable,
+                SequenceNumber(0),
+                Bytes::from_static(b"small"),
+            )
+            .unwrap();
+        let packets = outbox.drain();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].len(), 2);
+        for packet in packets {
+            let size: usize = packet.iter().map(Chunk::encoded_len).sum();
+            assert!(size <= MAX_PAYLOAD_SIZE);
+            assert!(size >= CHUNK_HEADER_SIZE);
+        }
+    }
+}
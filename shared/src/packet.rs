@@ -1,14 +1,69 @@
 use super::hexdump;
-use bytes::{Buf, BufMut, ByteOrder, Bytes, BytesMut, LittleEndian as LE, Reader, Writer};
+use crate::channel::Channel;
+use crate::crypto::{Cipher, TAG_SIZE};
+use bytes::{
+    BigEndian, Buf, BufMut, ByteOrder, Bytes, BytesMut, LittleEndian as LE, Reader, Writer,
+};
 use chrono::prelude::*;
 use crc::crc32;
 use pretty_hex::*;
-use snafu::{ensure, Snafu};
+use serde_derive::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, Snafu};
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::io::{Cursor, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use std::trace_macros;
 
+/// Identifies our datagrams, "BSPL" in ASCII
+pub const MAGIC: u32 = 0x4253_504c;
+
+/// Version of the wire format, bumped on every incompatible change
+pub const PROTOCOL_VERSION: u8 = 11;
+
+/// Set in the header format byte of packets with an encrypted message
+const ENCRYPTED_FLAG: u8 = 0x80;
+
+/// Set in the header format byte of packets stamped with the server's clock, see
+/// `Packet::clock_synced`
+const CLOCK_SYNCED_FLAG: u8 = 0x40;
+
+/// Offset of the connection ID, which directly follows the magic, version and format byte in
+/// every header format
+const CONNECTION_ID_OFFSET: usize = 6;
+
+/// Largest packet payload put into a single datagram, chosen so that packets stay well below
+/// the typical 1280-1500 byte path MTU
+pub const MAX_PAYLOAD_SIZE: usize = 1152;
+
+/// Size of the header preceding every chunk in a packet payload
+pub const CHUNK_HEADER_SIZE: usize = 9;
+
+/// Messages larger than this are split into fragments
+pub const MAX_FRAGMENT_SIZE: usize = 1024;
+
+/// Default upper bound for the size of a reassembled message
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// the fragment count of a message has to fit in a byte
+const _: () = assert!(MAX_MESSAGE_SIZE <= MAX_FRAGMENT_SIZE * u8::MAX as usize);
+
+/// Default time to wait for the missing fragments of a message
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Default for how far ahead of our clock a packet may be stamped, which allows for the error
+/// in the estimate of the remote's clock
+pub const MAX_FUTURE_SKEW: Duration = Duration::from_secs(2);
+
+/// Default for how long ago a packet may have been stamped, which allows for network delay
+pub const MAX_PACKET_AGE: Duration = Duration::from_secs(10);
+
+/// Maximum number of messages being reassembled at the same time
+const MAX_FRAGMENT_GROUPS: usize = 64;
+
 macro_rules! ensure_size {
     ($size:expr, $cur:expr) => {{
         let expected = $size;
@@ -18,6 +73,10 @@ macro_rules! ensure_size {
 }
 
 macro_rules! read {
+    (u8, $cur:expr) => {{
+        ensure_size!(1, $cur);
+        Ok($cur.get_u8())
+    }};
     ($type:ty, $cur:expr) => {{
         ensure_size!(std::mem::size_of::<$type>(), $cur);
         paste::expr! {
@@ -26,6 +85,27 @@ macro_rules! read {
     }};
 }
 
+/// Reads an unsigned LEB128 varint of at most 5 bytes
+fn read_varint(cur: &mut Cursor<Bytes>) -> Result<u32, PacketError> {
+    let mut value = 0u32;
+    for i in 0..5 {
+        let byte = read!(u8, cur)?;
+        value |= u32::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    InvalidVarint.fail()
+}
+
+fn put_varint(bytes: &mut BytesMut, mut value: u32) {
+    while value >= 0x80 {
+        bytes.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.put_u8(value as u8);
+}
+
 fn take(count: usize, cursor: Cursor<Bytes>) -> Result<Bytes, PacketError> {
     ensure_size!(count, cursor);
     Ok(cursor.get_ref().slice(
@@ -44,47 +124,436 @@ pub enum PacketError {
         computed
     ))]
     InvalidChecksum { received: u32, computed: u32 },
-    #[snafu(display("invalid timestamp"))]
-    InvalidTimestamp,
+    #[snafu(display("invalid timestamp {}", nanos))]
+    InvalidTimestamp { nanos: i64 },
+    #[snafu(display("timestamp {:?} ahead of our clock", ahead))]
+    TimestampInFuture { ahead: Duration },
+    #[snafu(display("timestamp expired {:?} ago", age))]
+    TimestampExpired { age: Duration },
+    #[snafu(display("timestamp from an unsynchronized clock after a synchronized one"))]
+    TimestampUnsynced,
+    #[snafu(display("bad magic {:#010x}, not a baseplate packet", magic))]
+    BadMagic { magic: u32 },
+    #[snafu(display(
+        "unsupported protocol version {} (expected {})",
+        version,
+        PROTOCOL_VERSION
+    ))]
+    UnsupportedVersion { version: u8 },
+    #[snafu(display("unknown header format {}", format))]
+    InvalidHeaderFormat { format: u8 },
+    #[snafu(display("varint longer than 5 bytes"))]
+    InvalidVarint,
+    #[snafu(display("error encrypting message"))]
+    EncryptionFailed,
+    #[snafu(display("sequence numbers exhausted, session keys have to be renegotiated"))]
+    NoncesExhausted,
+    #[snafu(display("unable to decrypt message"))]
+    DecryptionFailed,
+    #[snafu(display("message authentication failed"))]
+    AuthenticationFailed,
+    #[snafu(display("unknown channel {}", channel))]
+    InvalidChannel { channel: u8 },
+    #[snafu(display("invalid fragment {} of {}", index, count))]
+    InvalidFragment { index: u8, count: u8 },
+    #[snafu(display("message of {} bytes exceeds the maximum of {}", size, max))]
+    MessageTooLarge { size: usize, max: usize },
+}
+
+/// Encoding of the packet header, chosen by the sender and recorded in the header itself so that
+/// the receiver can decode either one.
+///
+/// `Full` writes fixed-width fields: 32-bit sequence numbers and length, and a 64-bit nanosecond
+/// timestamp. `Compact` writes the sequence numbers and length as varints, and the timestamp as
+/// the low 32 bits of its milliseconds, which the receiver resolves to the nearest matching
+/// instant of its own clock. This saves up to 13 bytes per packet, which adds up at high tick
+/// rates. Both formats carry the 4 byte connection ID, so that sessions can be found when the
+/// client's address changes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderFormat {
+    #[default]
+    Full,
+    Compact,
+}
+
+impl HeaderFormat {
+    pub fn id(self) -> u8 {
+        match self {
+            HeaderFormat::Full => 0,
+            HeaderFormat::Compact => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<HeaderFormat> {
+        match id {
+            0 => Some(HeaderFormat::Full),
+            1 => Some(HeaderFormat::Compact),
+            _ => None,
+        }
+    }
+
+    /// Environment variable selecting the header format used for sending, "full" or "compact"
+    pub const ENV_VAR: &'static str = "BASEPLATE_HEADER_FORMAT";
+
+    /// Reads the header format from `HeaderFormat::ENV_VAR`, falling back to the default when
+    /// it is unset
+    pub fn from_env() -> Result<HeaderFormat, String> {
+        match std::env::var(HeaderFormat::ENV_VAR) {
+            Ok(value) => value.parse(),
+            Err(_) => Ok(Default::default()),
+        }
+    }
+}
+
+impl FromStr for HeaderFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HeaderFormat, String> {
+        match s {
+            "full" => Ok(HeaderFormat::Full),
+            "compact" => Ok(HeaderFormat::Compact),
+            _ => Err(format!("unknown header format {:?}", s)),
+        }
+    }
+}
+
+/// Resolves a timestamp truncated to the low 32 bits of its milliseconds to the nearest
+/// matching instant around `now`, which works as long as the clocks are less than 24 days apart
+fn resolve_millis(millis: u32, now: DateTime<Utc>) -> DateTime<Utc> {
+    let now_millis = now.timestamp_millis();
+    let offset = (now_millis as u32).wrapping_sub(millis) as i32;
+    // within 24 days of `now`, so always representable
+    Utc.timestamp_millis_opt(now_millis - i64::from(offset))
+        .unwrap()
+}
+
+/// How far the timestamp of a received packet may be from our clock.
+///
+/// Rejecting packets stamped too long ago bounds the time during which a captured packet can
+/// be replayed. Replays that arrive sooner are caught by the `ReplayWindow`, as long as it still
+/// covers the sequence number, so `max_age` should be shorter than the time it takes to send
+/// `ReplayWindow::SIZE` packets at the lowest expected rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampBounds {
+    pub max_future_skew: Duration,
+    pub max_age: Duration,
+}
+
+impl Default for TimestampBounds {
+    fn default() -> TimestampBounds {
+        TimestampBounds {
+            max_future_skew: MAX_FUTURE_SKEW,
+            max_age: MAX_PACKET_AGE,
+        }
+    }
+}
+
+impl TimestampBounds {
+    pub fn check(&self, timestamp: DateTime<Utc>, now: DateTime<Utc>) -> Result<(), PacketError> {
+        let age = now.signed_duration_since(timestamp);
+        match age.to_std() {
+            Ok(age) => ensure!(age <= self.max_age, TimestampExpired { age }),
+            Err(_) => {
+                let ahead = (-age).to_std().unwrap_or_default();
+                ensure!(ahead <= self.max_future_skew, TimestampInFuture { ahead });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Identifies a session regardless of the address its packets come from, so that it survives
+/// the client's NAT mapping changing. Assigned by the server during the handshake.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ConnectionId(pub u32);
+
+impl ConnectionId {
+    /// Used by clients that have not been assigned an ID yet
+    pub const NONE: ConnectionId = ConnectionId(0);
+
+    pub fn is_none(self) -> bool {
+        self == ConnectionId::NONE
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{:08x}", self.0)
+    }
+}
+
+/// Packet sequence number with RFC 1982 serial number arithmetic.
+///
+/// Sequence numbers wrap around on overflow, and comparisons are done relative to half the
+/// number space: `a < b` if `b` is less than 2^31 steps ahead of `a`. Comparing two numbers
+/// exactly 2^31 apart is undefined, which is why this type is only `PartialOrd`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SequenceNumber(pub u32);
+
+impl SequenceNumber {
+    pub fn next(self) -> SequenceNumber {
+        self.wrapping_add(1)
+    }
+
+    pub fn wrapping_add(self, n: u32) -> SequenceNumber {
+        SequenceNumber(self.0.wrapping_add(n))
+    }
+
+    pub fn wrapping_sub(self, n: u32) -> SequenceNumber {
+        SequenceNumber(self.0.wrapping_sub(n))
+    }
+
+    /// Number of steps from `other` forward to `self`, modulo 2^32
+    pub fn distance(self, other: SequenceNumber) -> u32 {
+        self.0.wrapping_sub(other.0)
+    }
+}
+
+impl PartialOrd for SequenceNumber {
+    fn partial_cmp(&self, other: &SequenceNumber) -> Option<Ordering> {
+        const HALF: u32 = 1 << 31;
+        match self.distance(*other) {
+            0 => Some(Ordering::Equal),
+            HALF => None,
+            d if d < HALF => Some(Ordering::Greater),
+            _ => Some(Ordering::Less),
+        }
+    }
+}
+
+impl From<u32> for SequenceNumber {
+    fn from(n: u32) -> SequenceNumber {
+        SequenceNumber(n)
+    }
+}
+
+impl fmt::Display for SequenceNumber {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(fmt)
+    }
+}
+
+/// Position of a chunk's payload within a fragmented message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment {
+    pub index: u8,
+    pub count: u8,
+}
+
+impl Fragment {
+    /// The payload is a complete message
+    pub const WHOLE: Fragment = Fragment { index: 0, count: 1 };
+
+    pub fn is_whole(self) -> bool {
+        self.count == 1
+    }
+}
+
+/// A message, or a fragment of one, inside a packet payload. A single packet carries any
+/// number of chunks, possibly on different channels.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Chunk {
+    pub channel: Channel,
+    /// Sequence number of the message within its channel
+    pub channel_sequence: SequenceNumber,
+    pub fragment: Fragment,
+    pub payload: Bytes,
+}
+
+impl fmt::Debug for Chunk {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Chunk")
+            .field("channel", &self.channel)
+            .field("channel_sequence", &self.channel_sequence)
+            .field("fragment", &self.fragment)
+            .field("payload", &format!("<{} bytes>", self.payload.len()))
+            .finish()
+    }
+}
+
+impl Chunk {
+    pub fn encoded_len(&self) -> usize {
+        CHUNK_HEADER_SIZE + self.payload.len()
+    }
+
+    /// Encodes chunks into a packet payload
+    pub fn encode(chunks: &[Chunk]) -> Bytes {
+        let size = chunks.iter().map(Chunk::encoded_len).sum();
+        let mut bytes = BytesMut::with_capacity(size);
+        for chunk in chunks {
+            bytes.put_u8(chunk.channel.id());
+            bytes.put_u32_be(chunk.channel_sequence.0);
+            bytes.put_u8(chunk.fragment.index);
+            bytes.put_u8(chunk.fragment.count);
+            bytes.put_u16_be(chunk.payload.len() as u16);
+            bytes.put(&chunk.payload);
+        }
+        bytes.freeze()
+    }
+
+    /// Decodes every chunk in a packet payload, in order
+    pub fn decode(bytes: Bytes) -> Result<Vec<Chunk>, PacketError> {
+        let mut cur = Cursor::new(bytes);
+        let mut chunks = vec![];
+        while cur.has_remaining() {
+            let channel_id = read!(u8, cur)?;
+            let channel = Channel::from_id(channel_id).context(InvalidChannel {
+                channel: channel_id,
+            })?;
+            let channel_sequence = SequenceNumber(read!(u32, cur)?);
+            let fragment = Fragment {
+                index: read!(u8, cur)?,
+                count: read!(u8, cur)?,
+            };
+            ensure!(
+                fragment.index < fragment.count,
+                InvalidFragment {
+                    index: fragment.index,
+                    count: fragment.count
+                }
+            );
+            let length = read!(u16, cur)? as usize;
+            let payload = take(length, cur.clone())?;
+            cur.advance(length);
+            chunks.push(Chunk {
+                channel,
+                channel_sequence,
+                fragment,
+                payload,
+            });
+        }
+        Ok(chunks)
+    }
 }
 
 #[derive(Eq, PartialEq)]
 pub struct Packet {
-    pub sequence_number: u32,
+    pub connection_id: ConnectionId,
+    pub sequence_number: SequenceNumber,
+    /// Latest sequence number received from the remote
+    pub ack: SequenceNumber,
+    /// Bit `i` is set if `ack - 1 - i` has been received from the remote as well
+    pub ack_bits: u32,
     pub timestamp: DateTime<Utc>,
+    /// Whether `timestamp` is on the server's clock, which it is for every packet from the
+    /// server but only for those a client sends once it has synchronized its clock. Until
+    /// then the timestamp cannot be checked against `TimestampBounds`.
+    pub clock_synced: bool,
+    /// Encoded chunks, see `Chunk::decode`
     pub message: Bytes,
 }
 
 impl fmt::Debug for Packet {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Packet")
+            .field("connection_id", &self.connection_id)
             .field("sequence_number", &self.sequence_number)
+            .field("ack", &self.ack)
+            .field("ack_bits", &format!("{:#034b}", self.ack_bits))
             .field("timestamp", &self.timestamp)
+            .field("clock_synced", &self.clock_synced)
             .field("message", &format!("<{} bytes>", self.message.len()))
             .finish()
     }
 }
 
 impl Packet {
-    pub fn new(sequence_number: u32, message: Bytes) -> Packet {
+    pub fn new(
+        sequence_number: SequenceNumber,
+        (ack, ack_bits): (SequenceNumber, u32),
+        message: Bytes,
+    ) -> Packet {
         let _checksum = crc32::checksum_ieee(&message);
         let timestamp = Utc::now();
         Packet {
+            connection_id: ConnectionId::NONE,
             sequence_number,
+            ack,
+            ack_bits,
             timestamp,
+            clock_synced: false,
             message,
         }
     }
 
+    pub fn with_connection_id(mut self, connection_id: ConnectionId) -> Packet {
+        self.connection_id = connection_id;
+        self
+    }
+
+    /// Stamps the packet with a time other than our clock's, e.g. an estimate of the remote's
+    pub fn with_timestamp(mut self, timestamp: DateTime<Utc>) -> Packet {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Marks the timestamp as being on the server's clock, see `Packet::clock_synced`
+    pub fn with_clock_synced(mut self, clock_synced: bool) -> Packet {
+        self.clock_synced = clock_synced;
+        self
+    }
+
     pub fn from_bytes(bytes: Bytes) -> Result<Packet, PacketError> {
+        Packet::decode(bytes, None)
+    }
+
+    /// Reads the connection ID of an encoded packet without decoding the rest, to find the
+    /// session whose keys are needed to decode it
+    pub fn peek_connection_id(bytes: &[u8]) -> Option<ConnectionId> {
+        let header = bytes.get(..CONNECTION_ID_OFFSET + 4)?;
+        if BigEndian::read_u32(header) != MAGIC || header[4] != PROTOCOL_VERSION {
+            return None;
+        }
+        Some(ConnectionId(BigEndian::read_u32(
+            &header[CONNECTION_ID_OFFSET..],
+        )))
+    }
+
+    /// Decodes a packet, decrypting its message with `cipher` if given. Once a session has
+    /// keys, packets that are not encrypted are rejected.
+    pub fn decode(bytes: Bytes, cipher: Option<&Cipher>) -> Result<Packet, PacketError> {
         trace_macros!(true);
         let mut cur = Cursor::new(bytes);
 
-        let sequence_number = read!(u32, cur)?;
+        let magic = read!(u32, cur)?;
+        ensure!(magic == MAGIC, BadMagic { magic });
+        let version = read!(u8, cur)?;
+        ensure!(version == PROTOCOL_VERSION, UnsupportedVersion { version });
+        let format_byte = read!(u8, cur)?;
+        let encrypted = format_byte & ENCRYPTED_FLAG != 0;
+        let clock_synced = format_byte & CLOCK_SYNCED_FLAG != 0;
+        let format_id = format_byte & !(ENCRYPTED_FLAG | CLOCK_SYNCED_FLAG);
+        let format =
+            HeaderFormat::from_id(format_id).context(InvalidHeaderFormat { format: format_id })?;
+        let connection_id = ConnectionId(read!(u32, cur)?);
+        let (sequence_number, ack) = match format {
+            HeaderFormat::Full => (
+                SequenceNumber(read!(u32, cur)?),
+                SequenceNumber(read!(u32, cur)?),
+            ),
+            HeaderFormat::Compact => (
+                SequenceNumber(read_varint(&mut cur)?),
+                SequenceNumber(read_varint(&mut cur)?),
+            ),
+        };
+        let ack_bits = read!(u32, cur)?;
+        let checksum_offset = cur.position() as usize;
         let received_checksum = read!(u32, cur)?;
-        let message_length = read!(u32, cur)? as usize;
-        let timestamp = Utc.timestamp_nanos(read!(i64, cur)?);
-        let message = take(message_length, cur)?;
+        let (message_length, timestamp) = match format {
+            HeaderFormat::Full => {
+                let length = read!(u32, cur)? as usize;
+                let nanos = read!(i64, cur)?;
+                // nothing was sent before the epoch
+                ensure!(nanos >= 0, InvalidTimestamp { nanos });
+                (length, Utc.timestamp_nanos(nanos))
+            }
+            HeaderFormat::Compact => (
+                read_varint(&mut cur)? as usize,
+                resolve_millis(read!(u32, cur)?, Utc::now()),
+            ),
+        };
+        let header_length = cur.position() as usize;
+        let message = take(message_length, cur.clone())?;
 
         // Check that the received message matches the checksum transmitted in the header
         let computed_checksum = crc32::checksum_ieee(&message);
@@ -95,25 +564,244 @@ impl Packet {
                 computed: computed_checksum
             }
         );
+        let message = match (encrypted, cipher) {
+            (false, None) => message,
+            (true, Some(cipher)) => {
+                let mut header = BytesMut::from(&cur.get_ref()[..header_length]);
+                header[checksum_offset..checksum_offset + 4].copy_from_slice(&[0; 4]);
+                Bytes::from(cipher.decrypt(sequence_number, &header, &message)?)
+            }
+            (true, None) => return DecryptionFailed.fail(),
+            (false, Some(_)) => return AuthenticationFailed.fail(),
+        };
         trace_macros!(false);
         Ok(Packet {
+            connection_id,
             sequence_number,
+            ack,
+            ack_bits,
             timestamp,
+            clock_synced,
             message,
         })
     }
 
-    pub fn to_bytes(&self) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+    /// Encodes the packet with the given header format. `Compact` only keeps millisecond
+    /// precision of the timestamp.
+    pub fn to_bytes(&self, format: HeaderFormat) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+        self.encode(format, None)
+    }
+
+    /// Encodes the packet, encrypting its message with `cipher` if given. The header is
+    /// authenticated along with the message, except for the checksum which is computed over
+    /// the encrypted message.
+    pub fn encode(
+        &self,
+        format: HeaderFormat,
+        cipher: Option<&Cipher>,
+    ) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+        let (mut flags, message_length) = match cipher {
+            Some(_) => (ENCRYPTED_FLAG, self.message.len() + TAG_SIZE),
+            None => (0, self.message.len()),
+        };
+        if self.clock_synced {
+            flags |= CLOCK_SYNCED_FLAG;
+        }
         let mut bytes = BytesMut::with_capacity(65507);
-        bytes.put_u32_be(self.sequence_number);
-        bytes.put_u32_be(crc32::checksum_ieee(&self.message));
-        bytes.put_u32_be(self.message.len() as u32);
-        bytes.put_i64_be(self.timestamp.timestamp_nanos());
-        bytes.put(&self.message);
+        bytes.put_u32_be(MAGIC);
+        bytes.put_u8(PROTOCOL_VERSION);
+        bytes.put_u8(format.id() | flags);
+        bytes.put_u32_be(self.connection_id.0);
+        match format {
+            HeaderFormat::Full => {
+                bytes.put_u32_be(self.sequence_number.0);
+                bytes.put_u32_be(self.ack.0);
+            }
+            HeaderFormat::Compact => {
+                put_varint(&mut bytes, self.sequence_number.0);
+                put_varint(&mut bytes, self.ack.0);
+            }
+        }
+        bytes.put_u32_be(self.ack_bits);
+        // filled in once the message is final
+        let checksum_offset = bytes.len();
+        bytes.put_u32_be(0);
+        match format {
+            HeaderFormat::Full => {
+                bytes.put_u32_be(message_length as u32);
+                bytes.put_i64_be(self.timestamp.timestamp_nanos());
+            }
+            HeaderFormat::Compact => {
+                put_varint(&mut bytes, message_length as u32);
+                bytes.put_u32_be(self.timestamp.timestamp_millis() as u32);
+            }
+        }
+        let message = match cipher {
+            Some(cipher) => {
+                Bytes::from(cipher.encrypt(self.sequence_number, &bytes, &self.message)?)
+            }
+            None => self.message.clone(),
+        };
+        BigEndian::write_u32(
+            &mut bytes[checksum_offset..checksum_offset + 4],
+            crc32::checksum_ieee(&message),
+        );
+        bytes.put(&message);
         Ok(bytes.freeze())
     }
 }
 
+/// Datagram consisting of only the magic and our protocol version, sent in response to packets
+/// with a version we do not support. Decoding it with a different version yields
+/// `PacketError::UnsupportedVersion` carrying our version.
+pub fn version_rejection() -> Bytes {
+    let mut bytes = BytesMut::with_capacity(5);
+    bytes.put_u32_be(MAGIC);
+    bytes.put_u8(PROTOCOL_VERSION);
+    bytes.freeze()
+}
+
+/// Splits a message payload into fragments of at most `MAX_FRAGMENT_SIZE` bytes.
+///
+/// Payloads larger than `MAX_MESSAGE_SIZE` are refused, since a peer using the default limits
+/// would discard them after reassembly anyway.
+pub fn fragment(payload: &Bytes) -> Result<Vec<Bytes>, PacketError> {
+    let max = MAX_MESSAGE_SIZE;
+    ensure!(
+        payload.len() <= max,
+        MessageTooLarge {
+            size: payload.len(),
+            max
+        }
+    );
+    if payload.is_empty() {
+        return Ok(vec![payload.clone()]);
+    }
+    Ok((0..payload.len())
+        .step_by(MAX_FRAGMENT_SIZE)
+        .map(|start| payload.slice(start, payload.len().min(start + MAX_FRAGMENT_SIZE)))
+        .collect())
+}
+
+#[derive(Debug)]
+struct FragmentGroup {
+    fragments: Vec<Option<Bytes>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+/// Collects fragments until every part of a message has arrived.
+///
+/// Fragments are grouped by channel and channel sequence number. Groups that are still
+/// incomplete after the timeout are discarded, as are groups that would exceed the maximum
+/// message size.
+#[derive(Debug)]
+pub struct Reassembler {
+    groups: HashMap<(Channel, SequenceNumber), FragmentGroup>,
+    timeout: Duration,
+    max_size: usize,
+    expired: u64,
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new(FRAGMENT_TIMEOUT, MAX_MESSAGE_SIZE)
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, max_size: usize) -> Reassembler {
+        Reassembler {
+            groups: HashMap::new(),
+            timeout,
+            max_size,
+            expired: 0,
+        }
+    }
+
+    /// Returns a chunk with the complete message once its last missing fragment arrives
+    pub fn reassemble(
+        &mut self,
+        mut chunk: Chunk,
+        now: Instant,
+    ) -> Result<Option<Chunk>, PacketError> {
+        if chunk.fragment.is_whole() {
+            return Ok(Some(chunk));
+        }
+        self.expire(now);
+        let key = (chunk.channel, chunk.channel_sequence);
+        if !self.groups.contains_key(&key) && self.groups.len() >= MAX_FRAGMENT_GROUPS {
+            let oldest = self
+                .groups
+                .iter()
+                .min_by_key(|(_, group)| group.started)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.groups.remove(&oldest);
+                self.expired += 1;
+            }
+        }
+        let Fragment { index, count } = chunk.fragment;
+        let group = self.groups.entry(key).or_insert_with(|| FragmentGroup {
+            fragments: vec![None; count as usize],
+            received: 0,
+            size: 0,
+            started: now,
+        });
+        ensure!(
+            group.fragments.len() == count as usize,
+            InvalidFragment { index, count }
+        );
+        let slot = &mut group.fragments[index as usize];
+        if slot.is_some() {
+            // duplicate fragment
+            return Ok(None);
+        }
+        group.size += chunk.payload.len();
+        if group.size > self.max_size {
+            let size = group.size;
+            self.groups.remove(&key);
+            return MessageTooLarge {
+                size,
+                max: self.max_size,
+            }
+            .fail();
+        }
+        *slot = Some(chunk.payload.clone());
+        group.received += 1;
+        if group.received < group.fragments.len() {
+            return Ok(None);
+        }
+
+        let group = self.groups.remove(&key).unwrap();
+        let mut message = BytesMut::with_capacity(group.size);
+        for fragment in group.fragments {
+            message.extend_from_slice(&fragment.unwrap());
+        }
+        chunk.payload = message.freeze();
+        chunk.fragment = Fragment::WHOLE;
+        Ok(Some(chunk))
+    }
+
+    /// Discards incomplete messages whose first fragment arrived longer ago than the timeout,
+    /// returning how many were discarded
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let timeout = self.timeout;
+        let before = self.groups.len();
+        self.groups
+            .retain(|_, group| now.duration_since(group.started) < timeout);
+        let expired = before - self.groups.len();
+        self.expired += expired as u64;
+        expired
+    }
+
+    /// Number of incomplete messages discarded so far
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,26 +810,307 @@ mod tests {
     fn test_write() {
         let packet = Packet {
             message: Bytes::from_static(b"HELLO WORLD!"),
-            sequence_number: 5,
+            connection_id: ConnectionId::NONE,
+            sequence_number: SequenceNumber(5),
+            ack: SequenceNumber(3),
+            ack_bits: 0b1101,
             timestamp: Utc::now(),
+            clock_synced: false,
         };
-        hexdump!(packet.to_bytes().unwrap());
+        hexdump!(packet.to_bytes(HeaderFormat::Full).unwrap());
     }
 
     #[test]
     fn test_roundtrip_id() {
         let packet = Packet {
             message: Bytes::from_static(b"HELLO WORLD!"),
-            sequence_number: 5,
+            connection_id: ConnectionId(0xdead_beef),
+            sequence_number: SequenceNumber(5),
+            ack: SequenceNumber(3),
+            ack_bits: 0b1101,
             timestamp: Utc::now(),
+            clock_synced: true,
         };
-        let encoded = packet.to_bytes().unwrap();
+        let encoded = packet.to_bytes(HeaderFormat::Full).unwrap();
         hexdump!(encoded);
         let decoded = Packet::from_bytes(encoded).unwrap();
-        hexdump!(decoded.to_bytes().unwrap());
+        hexdump!(decoded.to_bytes(HeaderFormat::Full).unwrap());
         assert_eq!(decoded, packet);
     }
 
+    #[test]
+    fn test_compact_header() {
+        let packet = Packet::new(
+            SequenceNumber(300),
+            (SequenceNumber(70000), 0b1101),
+            Bytes::from_static(b"HELLO WORLD!"),
+        );
+        let full = packet.to_bytes(HeaderFormat::Full).unwrap();
+        let compact = packet.to_bytes(HeaderFormat::Compact).unwrap();
+        hexdump!(compact);
+        // 2 + 3 + 1 + 4 bytes instead of 4 + 4 + 4 + 8
+        assert_eq!(full.len() - compact.len(), 10);
+
+        let decoded = Packet::from_bytes(compact).unwrap();
+        assert_eq!(decoded.sequence_number, packet.sequence_number);
+        assert_eq!(decoded.ack, packet.ack);
+        assert_eq!(decoded.ack_bits, packet.ack_bits);
+        assert_eq!(decoded.message, packet.message);
+        assert_eq!(
+            decoded.timestamp.timestamp_millis(),
+            packet.timestamp.timestamp_millis()
+        );
+    }
+
+    #[test]
+    fn test_peek_connection_id() {
+        let packet = Packet::new(
+            SequenceNumber(1),
+            (SequenceNumber(0), 0),
+            Bytes::from_static(b"HELLO"),
+        )
+        .with_connection_id(ConnectionId(7));
+        for &format in &[HeaderFormat::Full, HeaderFormat::Compact] {
+            let encoded = packet.to_bytes(format).unwrap();
+            assert_eq!(Packet::peek_connection_id(&encoded), Some(ConnectionId(7)));
+            assert_eq!(Packet::peek_connection_id(&encoded[..8]), None);
+        }
+        assert_eq!(Packet::peek_connection_id(&version_rejection()), None);
+    }
+
+    #[test]
+    fn test_timestamp_bounds() {
+        let bounds = TimestampBounds::default();
+        let now = Utc::now();
+        let ms = chrono::Duration::milliseconds;
+        assert!(bounds.check(now, now).is_ok());
+        assert!(bounds.check(now - ms(9_000), now).is_ok());
+        assert!(bounds.check(now + ms(1_500), now).is_ok());
+        match bounds.check(now - ms(11_000), now) {
+            Err(PacketError::TimestampExpired { age }) => {
+                assert_eq!(age, Duration::from_secs(11))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match bounds.check(now + ms(3_000), now) {
+            Err(PacketError::TimestampInFuture { ahead }) => {
+                assert_eq!(ahead, Duration::from_secs(3))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let packet = Packet::new(
+            SequenceNumber(1),
+            (SequenceNumber(0), 0),
+            Bytes::from_static(b"HELLO"),
+        )
+        .with_timestamp(Utc.timestamp_nanos(-1));
+        match Packet::from_bytes(packet.to_bytes(HeaderFormat::Full).unwrap()) {
+            Err(PacketError::InvalidTimestamp { nanos: -1 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let client = Cipher::new(&[1; 32], &[2; 32]);
+        let server = Cipher::new(&[2; 32], &[1; 32]);
+        let packet = Packet::new(
+            SequenceNumber(12),
+            (SequenceNumber(4), 0b11),
+            Bytes::from_static(b"HELLO WORLD!"),
+        );
+        for &format in &[HeaderFormat::Full, HeaderFormat::Compact] {
+            let encrypted = packet.encode(format, Some(&client)).unwrap();
+            hexdump!(encrypted);
+            let decoded = Packet::decode(encrypted.clone(), Some(&server)).unwrap();
+            assert_eq!(decoded.message, packet.message);
+            assert_eq!(decoded.ack_bits, packet.ack_bits);
+
+            match Packet::from_bytes(encrypted.clone()) {
+                Err(PacketError::DecryptionFailed) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+
+            // flip a bit in the header, which the checksum does not cover
+            let mut forged = BytesMut::from(encrypted);
+            forged[9] ^= 1;
+            match Packet::decode(forged.freeze(), Some(&server)) {
+                Err(PacketError::AuthenticationFailed) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+
+        let plaintext = packet.to_bytes(HeaderFormat::Full).unwrap();
+        match Packet::decode(plaintext, Some(&server)) {
+            Err(PacketError::AuthenticationFailed) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_varint() {
+        for &value in &[0, 127, 128, 16383, 16384, u32::MAX] {
+            let mut bytes = BytesMut::new();
+            put_varint(&mut bytes, value);
+            let mut cur = Cursor::new(bytes.freeze());
+            assert_eq!(read_varint(&mut cur).unwrap(), value);
+            assert!(!cur.has_remaining());
+        }
+        let mut cur = Cursor::new(Bytes::from_static(&[0xff; 6]));
+        match read_varint(&mut cur) {
+            Err(PacketError::InvalidVarint) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_resolve_millis() {
+        let now = Utc.timestamp_millis_opt(1 << 40).unwrap();
+        let earlier = now - chrono::Duration::milliseconds(1500);
+        let later = now + chrono::Duration::milliseconds(20);
+        assert_eq!(
+            resolve_millis(earlier.timestamp_millis() as u32, now),
+            earlier
+        );
+        assert_eq!(resolve_millis(later.timestamp_millis() as u32, now), later);
+    }
+
+    #[test]
+    fn test_sequence_wraparound() {
+        let max = SequenceNumber(u32::MAX);
+        assert_eq!(max.next(), SequenceNumber(0));
+        assert!(max.next() > max);
+        assert!(SequenceNumber(5) > max);
+        assert!(max < SequenceNumber(5));
+        assert!(SequenceNumber(1) < SequenceNumber(2));
+        assert_eq!(SequenceNumber(3).distance(max), 4);
+        assert_eq!(
+            SequenceNumber(0).partial_cmp(&SequenceNumber(1 << 31)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_chunk_roundtrip() {
+        let chunks = vec![
+            Chunk {
+                channel: Channel::ReliableOrdered,
+                channel_sequence: SequenceNumber(2),
+                fragment: Fragment { index: 1, count: 3 },
+                payload: Bytes::from_static(b"HELLO"),
+            },
+            Chunk {
+                channel: Channel::Unreliable,
+                channel_sequence: SequenceNumber(9),
+                fragment: Fragment::WHOLE,
+                payload: Bytes::from_static(b"WORLD!"),
+            },
+        ];
+        let encoded = Chunk::encode(&chunks);
+        assert_eq!(encoded.len(), 2 * CHUNK_HEADER_SIZE + 11);
+        assert_eq!(Chunk::decode(encoded).unwrap(), chunks);
+    }
+
+    fn fragments(payload: &Bytes) -> Vec<Chunk> {
+        let parts = fragment(payload).unwrap();
+        let count = parts.len() as u8;
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, payload)| Chunk {
+                channel: Channel::Unreliable,
+                channel_sequence: SequenceNumber(7),
+                fragment: Fragment {
+                    index: index as u8,
+                    count,
+                },
+                payload,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let payload = Bytes::from(vec![42u8; MAX_FRAGMENT_SIZE * 2 + 10]);
+        let mut chunks = fragments(&payload);
+        assert_eq!(chunks.len(), 3);
+        chunks.reverse();
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        let mut complete = vec![];
+        for chunk in chunks {
+            complete.extend(reassembler.reassemble(chunk, now).unwrap());
+        }
+        assert_eq!(complete.len(), 1);
+        assert_eq!(complete[0].payload, payload);
+        assert_eq!(complete[0].fragment, Fragment::WHOLE);
+    }
+
+    #[test]
+    fn test_fragment_limit() {
+        let payload = Bytes::from(vec![42u8; MAX_MESSAGE_SIZE]);
+        assert_eq!(
+            fragment(&payload).unwrap().len(),
+            MAX_MESSAGE_SIZE / MAX_FRAGMENT_SIZE
+        );
+        let payload = Bytes::from(vec![42u8; MAX_MESSAGE_SIZE + 1]);
+        match fragment(&payload) {
+            Err(PacketError::MessageTooLarge { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reassembly_limits() {
+        let payload = Bytes::from(vec![42u8; MAX_FRAGMENT_SIZE * 3]);
+        let now = Instant::now();
+
+        let mut reassembler = Reassembler::new(FRAGMENT_TIMEOUT, MAX_FRAGMENT_SIZE * 2);
+        let results: Vec<_> = fragments(&payload)
+            .into_iter()
+            .map(|chunk| reassembler.reassemble(chunk, now))
+            .collect();
+        match results[2] {
+            Err(PacketError::MessageTooLarge { .. }) => {}
+            ref other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut reassembler = Reassembler::default();
+        let mut chunks = fragments(&payload);
+        chunks.pop();
+        for chunk in chunks {
+            assert!(reassembler.reassemble(chunk, now).unwrap().is_none());
+        }
+        assert_eq!(reassembler.expire(now + FRAGMENT_TIMEOUT), 1);
+        assert_eq!(reassembler.expired(), 1);
+    }
+
+    #[test]
+    fn test_magic_and_version() {
+        let packet = Packet::new(
+            SequenceNumber(1),
+            (SequenceNumber(0), 0),
+            Bytes::from_static(b"HELLO"),
+        );
+        let mut encoded = BytesMut::from(packet.to_bytes(HeaderFormat::Full).unwrap());
+
+        encoded[4] = PROTOCOL_VERSION + 1;
+        match Packet::from_bytes(encoded.clone().freeze()) {
+            Err(PacketError::UnsupportedVersion { version }) => {
+                assert_eq!(version, PROTOCOL_VERSION + 1)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        encoded[0] = b'X';
+        match Packet::from_bytes(encoded.freeze()) {
+            Err(PacketError::BadMagic { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
     #[test]
     fn test_bad_input() {
         match Packet::from_bytes(Bytes::from_static(
@@ -1,12 +1,94 @@
-use super::handshake::HandshakeMessage;
+use super::handshake::{Cookie, HandshakeMessage};
+use chrono::{DateTime, Utc};
 use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum Message {
-    Connect,
+    /// Requests a session. The server only allocates one once the client echoes a cookie it
+    /// issued to the client's address, and answers anything else with `Cookie`. The first
+    /// request carries an all-zero cookie, which pads it to the size of the answer so that
+    /// spoofed requests are not amplified.
+    Connect {
+        cookie: Cookie,
+    },
+    /// Cookie to echo in the next `Connect`, sent without allocating any state
+    Cookie(Cookie),
     Disconnect,
     Handshake(HandshakeMessage),
     Heartbeat,
     Refresh(Vec<(f32, f32)>),
-    Move { dx: f32, dy: f32 },
+    Move {
+        dx: f32,
+        dy: f32,
+    },
+    /// Sent by the server to a new address a connection's packets arrive from, which it only
+    /// migrates the session to once the client echoes the data in `PathResponse`
+    PathChallenge(u64),
+    PathResponse(u64),
+    /// Starts a clock synchronization exchange, carrying the time the client sent it, see
+    /// `ClockSync`
+    TimeRequest(DateTime<Utc>),
+    /// Answers `TimeRequest` with the time the server received it. The time the server sent
+    /// the answer is the timestamp of the packet carrying it.
+    TimeResponse {
+        client_sent: DateTime<Utc>,
+        server_received: DateTime<Utc>,
+    },
+}
+
+/// Type of a message without its contents, see `HandshakeState::allowed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Connect,
+    Cookie,
+    Disconnect,
+    Handshake,
+    Heartbeat,
+    Refresh,
+    Move,
+    PathChallenge,
+    PathResponse,
+    TimeRequest,
+    TimeResponse,
+}
+
+impl FromStr for MessageKind {
+    type Err = String;
+
+    /// Parses the name of a message type, as in `Refresh`
+    fn from_str(s: &str) -> Result<MessageKind, String> {
+        match s {
+            "Connect" => Ok(MessageKind::Connect),
+            "Cookie" => Ok(MessageKind::Cookie),
+            "Disconnect" => Ok(MessageKind::Disconnect),
+            "Handshake" => Ok(MessageKind::Handshake),
+            "Heartbeat" => Ok(MessageKind::Heartbeat),
+            "Refresh" => Ok(MessageKind::Refresh),
+            "Move" => Ok(MessageKind::Move),
+            "PathChallenge" => Ok(MessageKind::PathChallenge),
+            "PathResponse" => Ok(MessageKind::PathResponse),
+            "TimeRequest" => Ok(MessageKind::TimeRequest),
+            "TimeResponse" => Ok(MessageKind::TimeResponse),
+            _ => Err(format!("unknown message type {:?}", s)),
+        }
+    }
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::Connect { .. } => MessageKind::Connect,
+            Message::Cookie(_) => MessageKind::Cookie,
+            Message::Disconnect => MessageKind::Disconnect,
+            Message::Handshake(_) => MessageKind::Handshake,
+            Message::Heartbeat => MessageKind::Heartbeat,
+            Message::Refresh(_) => MessageKind::Refresh,
+            Message::Move { .. } => MessageKind::Move,
+            Message::PathChallenge(_) => MessageKind::PathChallenge,
+            Message::PathResponse(_) => MessageKind::PathResponse,
+            Message::TimeRequest(_) => MessageKind::TimeRequest,
+            Message::TimeResponse { .. } => MessageKind::TimeResponse,
+        }
+    }
 }
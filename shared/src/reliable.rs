@@ -0,0 +1,231 @@
+use crate::ack::Delivery;
+use crate::packet::{Fragment, SequenceNumber};
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How long to wait for an acknowledgement before sending a reliable message again
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// How far ahead of the next expected message the receiver is willing to buffer
+pub const MAX_BUFFERED: u32 = 1024;
+
+#[derive(Debug)]
+struct Pending {
+    id: SequenceNumber,
+    payload: Bytes,
+    sent_at: Instant,
+    lost: bool,
+    /// Which fragments of the message have been acknowledged
+    acked: Vec<bool>,
+}
+
+impl Pending {
+    fn delivered(&self) -> bool {
+        !self.acked.is_empty() && self.acked.iter().all(|acked| *acked)
+    }
+}
+
+/// Sending half of a reliable-ordered channel.
+///
+/// Every message is kept until the packets carrying each of its fragments have been
+/// acknowledged. Messages are retransmitted when a packet carrying them is reported lost, or
+/// when no acknowledgement has arrived within the retransmit timeout.
+#[derive(Debug)]
+pub struct ReliableSender {
+    next_id: SequenceNumber,
+    pending: VecDeque<Pending>,
+    /// Maps sent packets to the reliable message fragments they carried
+    carriers: HashMap<SequenceNumber, Vec<(SequenceNumber, Fragment)>>,
+    timeout: Duration,
+}
+
+impl Default for ReliableSender {
+    fn default() -> ReliableSender {
+        ReliableSender::new(RETRANSMIT_TIMEOUT)
+    }
+}
+
+impl ReliableSender {
+    pub fn new(timeout: Duration) -> ReliableSender {
+        ReliableSender {
+            next_id: SequenceNumber(0),
+            pending: VecDeque::new(),
+            carriers: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Queues a message for reliable delivery, returning the id it should be sent with
+    pub fn push(&mut self, payload: Bytes, now: Instant) -> SequenceNumber {
+        let id = self.next_id;
+        self.next_id = id.next();
+        self.pending.push_back(Pending {
+            id,
+            payload,
+            sent_at: now,
+            lost: false,
+            acked: vec![],
+        });
+        id
+    }
+
+    /// Records that a fragment of message `id` was sent in packet `sequence`
+    pub fn on_sent(&mut self, id: SequenceNumber, fragment: Fragment, sequence: SequenceNumber) {
+        if let Some(pending) = self.pending.iter_mut().find(|p| p.id == id) {
+            if pending.acked.len() != fragment.count as usize {
+                pending.acked = vec![false; fragment.count as usize];
+            }
+            self.carriers
+                .entry(sequence)
+                .or_default()
+                .push((id, fragment));
+        }
+    }
+
+    pub fn on_delivery(&mut self, delivery: Delivery) {
+        match delivery {
+            Delivery::Acked(sequence) => {
+                for (id, fragment) in self.carriers.remove(&sequence).unwrap_or_default() {
+                    if let Some(pending) = self.pending.iter_mut().find(|p| p.id == id) {
+                        if let Some(acked) = pending.acked.get_mut(fragment.index as usize) {
+                            *acked = true;
+                        }
+                    }
+                }
+                self.pending.retain(|pending| !pending.delivered());
+            }
+            Delivery::Lost(sequence) => {
+                for (id, _) in self.carriers.remove(&sequence).unwrap_or_default() {
+                    if let Some(pending) = self.pending.iter_mut().find(|p| p.id == id) {
+                        pending.lost = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Messages that need to be sent again, oldest first
+    pub fn due(&mut self, now: Instant) -> Vec<(SequenceNumber, Bytes)> {
+        let timeout = self.timeout;
+        self.pending
+            .iter_mut()
+            .filter(|pending| pending.lost || now.duration_since(pending.sent_at) >= timeout)
+            .map(|pending| {
+                pending.sent_at = now;
+                pending.lost = false;
+                (pending.id, pending.payload.clone())
+            })
+            .collect()
+    }
+
+    /// Number of messages still waiting for an acknowledgement
+    pub fn unacked(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Receiving half of a reliable-ordered channel.
+///
+/// Messages arriving ahead of a gap are buffered until the gap is filled, and messages that
+/// have already been received are dropped.
+#[derive(Debug, Default)]
+pub struct ReliableReceiver {
+    next_id: SequenceNumber,
+    buffered: HashMap<SequenceNumber, Bytes>,
+    duplicates: u64,
+}
+
+impl ReliableReceiver {
+    pub fn new() -> ReliableReceiver {
+        Default::default()
+    }
+
+    /// Accepts message `id`, returning every message that is now deliverable in order
+    pub fn receive(&mut self, id: SequenceNumber, payload: Bytes) -> Vec<Bytes> {
+        if id < self.next_id || self.buffered.contains_key(&id) {
+            self.duplicates += 1;
+            return vec![];
+        }
+        if id.distance(self.next_id) >= MAX_BUFFERED {
+            // Too far ahead, the sender will retransmit it later
+            return vec![];
+        }
+        self.buffered.insert(id, payload);
+        let mut deliverable = vec![];
+        while let Some(payload) = self.buffered.remove(&self.next_id) {
+            deliverable.push(payload);
+            self.next_id = self.next_id.next();
+        }
+        deliverable
+    }
+
+    /// Number of retransmitted messages that had already been received
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retransmit() {
+        let now = Instant::now();
+        let mut sender = ReliableSender::new(Duration::from_millis(100));
+        let first = sender.push(Bytes::from_static(b"first"), now);
+        let second = sender.push(Bytes::from_static(b"second"), now);
+        sender.on_sent(first, Fragment::WHOLE, SequenceNumber(10));
+        sender.on_sent(second, Fragment::WHOLE, SequenceNumber(11));
+        assert!(sender.due(now).is_empty());
+
+        sender.on_delivery(Delivery::Acked(SequenceNumber(10)));
+        sender.on_delivery(Delivery::Lost(SequenceNumber(11)));
+        assert_eq!(
+            sender.due(now),
+            vec![(second, Bytes::from_static(b"second"))]
+        );
+        assert!(sender.due(now).is_empty());
+        assert_eq!(
+            sender.due(now + Duration::from_millis(100)),
+            vec![(second, Bytes::from_static(b"second"))]
+        );
+
+        sender.on_sent(second, Fragment::WHOLE, SequenceNumber(12));
+        sender.on_delivery(Delivery::Acked(SequenceNumber(12)));
+        assert_eq!(sender.unacked(), 0);
+    }
+
+    #[test]
+    fn test_fragmented() {
+        let now = Instant::now();
+        let mut sender = ReliableSender::default();
+        let id = sender.push(Bytes::from_static(b"large"), now);
+        sender.on_sent(id, Fragment { index: 0, count: 2 }, SequenceNumber(1));
+        sender.on_sent(id, Fragment { index: 1, count: 2 }, SequenceNumber(2));
+        sender.on_delivery(Delivery::Acked(SequenceNumber(2)));
+        assert_eq!(sender.unacked(), 1);
+        sender.on_delivery(Delivery::Acked(SequenceNumber(1)));
+        assert_eq!(sender.unacked(), 0);
+    }
+
+    #[test]
+    fn test_in_order_delivery() {
+        let mut receiver = ReliableReceiver::new();
+        assert!(receiver
+            .receive(SequenceNumber(1), Bytes::from_static(b"b"))
+            .is_empty());
+        assert!(receiver
+            .receive(SequenceNumber(1), Bytes::from_static(b"b"))
+            .is_empty());
+        assert_eq!(
+            receiver.receive(SequenceNumber(0), Bytes::from_static(b"a")),
+            vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")]
+        );
+        assert!(receiver
+            .receive(SequenceNumber(0), Bytes::from_static(b"a"))
+            .is_empty());
+        assert_eq!(receiver.duplicates(), 2);
+    }
+}
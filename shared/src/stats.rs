@@ -0,0 +1,243 @@
+use crate::ack::Delivery;
+use crate::packet::SequenceNumber;
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Upper bound for send times kept around, matching the packets `AckTracker` keeps in flight
+const MAX_TRACKED: usize = 1024;
+
+/// Latency, loss and throughput of a connection, as seen from one end.
+///
+/// Round-trip times are sampled from acknowledged packets and smoothed as in RFC 6298. Acks
+/// ride on the next packet the remote sends, which both ends do every tick they have received
+/// anything in, so samples include up to a tick of the remote's. Jitter is the
+/// interarrival jitter of RFC 3550, computed from the send timestamps of received packets. As
+/// it only depends on differences between timestamps, the clocks of both ends need not agree.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    sent_at: VecDeque<(SequenceNumber, Instant)>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// Transit time of the previous packet received, in nanoseconds
+    transit: Option<i64>,
+    jitter: f64,
+    acked: u64,
+    lost: u64,
+    packets_sent: u64,
+    packets_received: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl ConnectionStats {
+    pub fn new() -> ConnectionStats {
+        Default::default()
+    }
+
+    pub fn on_send(&mut self, sequence: SequenceNumber, size: usize, now: Instant) {
+        self.packets_sent += 1;
+        self.bytes_sent += size as u64;
+        self.sent_at.push_back((sequence, now));
+        if self.sent_at.len() > MAX_TRACKED {
+            self.sent_at.pop_front();
+        }
+    }
+
+    /// Records a packet stamped with `timestamp` by the remote, which arrived at `now`
+    pub fn on_receive(&mut self, timestamp: DateTime<Utc>, size: usize, now: DateTime<Utc>) {
+        self.packets_received += 1;
+        self.bytes_received += size as u64;
+        let transit = now.signed_duration_since(timestamp).num_nanoseconds();
+        if let (Some(previous), Some(transit)) = (self.transit, transit) {
+            let difference = (transit - previous).abs() as f64;
+            self.jitter += (difference - self.jitter) / 16.0;
+        }
+        self.transit = transit;
+    }
+
+    pub fn on_delivery(&mut self, delivery: Delivery, now: Instant) {
+        let sequence = match delivery {
+            Delivery::Acked(sequence) | Delivery::Lost(sequence) => sequence,
+        };
+        let i = match self.sent_at.iter().position(|(sent, _)| *sent == sequence) {
+            Some(i) => i,
+            // already accounted for
+            None => return,
+        };
+        let (_, sent_at) = self.sent_at.remove(i).unwrap();
+        match delivery {
+            Delivery::Acked(_) => {
+                self.acked += 1;
+                self.on_rtt(now.duration_since(sent_at));
+            }
+            Delivery::Lost(_) => self.lost += 1,
+        }
+    }
+
+    fn on_rtt(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + deviation / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+    }
+
+    /// Smoothed round-trip time, once a packet has been acknowledged
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Mean deviation of the round-trip time
+    pub fn rtt_variance(&self) -> Duration {
+        self.rttvar
+    }
+
+    pub fn jitter(&self) -> Duration {
+        Duration::from_nanos(self.jitter as u64)
+    }
+
+    /// Percentage of sent packets known to be lost, out of those whose fate is known
+    pub fn loss(&self) -> f64 {
+        let known = self.acked + self.lost;
+        if known == 0 {
+            0.0
+        } else {
+            100.0 * self.lost as f64 / known as f64
+        }
+    }
+
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    pub fn packets_received(&self) -> u64 {
+        self.packets_received
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Current figures, without the send times kept for measuring round trips
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            rtt: self.rtt(),
+            rtt_variance: self.rtt_variance(),
+            jitter: self.jitter(),
+            loss: self.loss(),
+            packets_sent: self.packets_sent,
+            packets_received: self.packets_received,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionStats {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.snapshot().fmt(fmt)
+    }
+}
+
+/// Figures of `ConnectionStats` at one point in time, which are cheap to copy around, e.g. to
+/// show them every frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsSnapshot {
+    /// Smoothed round-trip time, once a packet has been acknowledged
+    pub rtt: Option<Duration>,
+    pub rtt_variance: Duration,
+    pub jitter: Duration,
+    /// Percentage of sent packets known to be lost
+    pub loss: f64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        match self.rtt {
+            Some(rtt) => write!(
+                fmt,
+                "rtt {:.1}ms ± {:.1}ms",
+                millis(rtt),
+                millis(self.rtt_variance)
+            )?,
+            None => write!(fmt, "rtt ?")?,
+        }
+        write!(
+            fmt,
+            ", jitter {:.1}ms, loss {:.1}%, {} B in, {} B out",
+            millis(self.jitter),
+            self.loss,
+            self.bytes_received,
+            self.bytes_sent
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt() {
+        let mut stats = ConnectionStats::new();
+        let start = Instant::now();
+        for i in 0..4 {
+            stats.on_send(SequenceNumber(i), 100, start);
+        }
+        let ms = Duration::from_millis;
+        stats.on_delivery(Delivery::Acked(SequenceNumber(0)), start + ms(80));
+        assert_eq!(stats.rtt(), Some(ms(80)));
+        assert_eq!(stats.rtt_variance(), ms(40));
+        stats.on_delivery(Delivery::Acked(SequenceNumber(1)), start + ms(160));
+        assert_eq!(stats.rtt(), Some(ms(90)));
+        assert_eq!(stats.rtt_variance(), ms(50));
+        // acked twice, which only counts once
+        stats.on_delivery(Delivery::Acked(SequenceNumber(1)), start + ms(1000));
+        assert_eq!(stats.rtt(), Some(ms(90)));
+
+        stats.on_delivery(Delivery::Lost(SequenceNumber(2)), start + ms(1000));
+        assert_eq!(stats.rtt(), Some(ms(90)));
+        assert_eq!(stats.loss(), 100.0 / 3.0);
+        assert_eq!(stats.bytes_sent(), 400);
+        assert_eq!(stats.packets_sent(), 4);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.rtt, Some(ms(90)));
+        assert_eq!(snapshot.packets_sent, 4);
+        assert_eq!(snapshot.to_string(), stats.to_string());
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut stats = ConnectionStats::new();
+        let start = Utc::now();
+        let ms = chrono::Duration::milliseconds;
+        // sent every 10ms, with a transit time alternating between 20 and 36 ms
+        for i in 0..64 {
+            let sent = start + ms(10 * i);
+            let transit = if i % 2 == 0 { ms(20) } else { ms(36) };
+            stats.on_receive(sent, 50, sent + transit);
+        }
+        let jitter = stats.jitter().as_secs_f64() * 1000.0;
+        assert!(jitter > 15.0 && jitter <= 16.0, "jitter {}", jitter);
+        assert_eq!(stats.bytes_received(), 64 * 50);
+        assert_eq!(stats.packets_received(), 64);
+    }
+}
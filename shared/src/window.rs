@@ -0,0 +1,315 @@
+use crate::packet::{Packet, PacketError, SequenceNumber, TimestampBounds};
+use chrono::prelude::*;
+
+/// Outcome of offering a sequence number to a `ReplayWindow`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Acceptance {
+    /// Newer than anything seen so far
+    InOrder,
+    /// Older than the latest packet, but not seen before
+    Reordered,
+    /// Already seen
+    Duplicate,
+    /// Too old to be tracked by the window
+    Stale,
+}
+
+impl Acceptance {
+    pub fn is_accepted(self) -> bool {
+        match self {
+            Acceptance::InOrder | Acceptance::Reordered => true,
+            Acceptance::Duplicate | Acceptance::Stale => false,
+        }
+    }
+}
+
+/// Sliding window receiver for packet sequence numbers.
+///
+/// Keeps the latest sequence number received along with a bitmap of the 64 sequence numbers
+/// up to and including it, where bit `i` is set if `latest - i` has been seen. Late packets
+/// are accepted as long as they still fall within the window and have not been seen before.
+#[derive(Debug, Default, Clone)]
+pub struct ReplayWindow {
+    latest: Option<SequenceNumber>,
+    bitmap: u64,
+    reordered: u64,
+    duplicates: u64,
+    stale: u64,
+    expired: u64,
+    premature: u64,
+    unsynced: u64,
+    /// Whether a packet stamped with the server's clock has been accepted
+    clock_synced: bool,
+}
+
+impl ReplayWindow {
+    pub const SIZE: u32 = 64;
+
+    pub fn new() -> ReplayWindow {
+        Default::default()
+    }
+
+    pub fn accept(&mut self, sequence: SequenceNumber) -> Acceptance {
+        let acceptance = match self.latest {
+            None => {
+                self.latest = Some(sequence);
+                self.bitmap = 1;
+                Acceptance::InOrder
+            }
+            Some(latest) if sequence > latest => {
+                let shift = sequence.distance(latest);
+                self.bitmap = if shift < Self::SIZE {
+                    self.bitmap << shift
+                } else {
+                    0
+                };
+                self.bitmap |= 1;
+                self.latest = Some(sequence);
+                Acceptance::InOrder
+            }
+            Some(latest) => {
+                // Also covers numbers exactly half the sequence space away, which are unordered
+                let offset = latest.distance(sequence);
+                if offset >= Self::SIZE {
+                    Acceptance::Stale
+                } else if self.bitmap & (1 << offset) != 0 {
+                    Acceptance::Duplicate
+                } else {
+                    self.bitmap |= 1 << offset;
+                    Acceptance::Reordered
+                }
+            }
+        };
+        match acceptance {
+            Acceptance::Reordered => self.reordered += 1,
+            Acceptance::Duplicate => self.duplicates += 1,
+            Acceptance::Stale => self.stale += 1,
+            Acceptance::InOrder => {}
+        }
+        acceptance
+    }
+
+    /// Offers a packet to the window once its timestamp has been checked against `bounds`.
+    /// Together they reject replayed packets: those the window still covers are duplicates, and
+    /// those it no longer covers should have expired by then. Packets with a rejected timestamp
+    /// leave the window as it was.
+    ///
+    /// The timestamp of a packet that is not `Packet::clock_synced` cannot be checked, as it
+    /// comes from a client that has yet to synchronize its clock with the server's, which it
+    /// does over these very packets. Once a synchronized packet has been accepted, the sender
+    /// has no reason to send any other, so those are rejected from then on.
+    pub fn accept_packet(
+        &mut self,
+        packet: &Packet,
+        bounds: &TimestampBounds,
+        now: DateTime<Utc>,
+    ) -> Result<Acceptance, PacketError> {
+        let checked = match (packet.clock_synced, self.clock_synced) {
+            (true, _) => bounds.check(packet.timestamp, now),
+            (false, false) => Ok(()),
+            (false, true) => Err(PacketError::TimestampUnsynced),
+        };
+        match checked {
+            Ok(()) => {
+                let acceptance = self.accept(packet.sequence_number);
+                if packet.clock_synced && acceptance.is_accepted() {
+                    self.clock_synced = true;
+                }
+                Ok(acceptance)
+            }
+            Err(err) => {
+                match err {
+                    PacketError::TimestampInFuture { .. } => self.premature += 1,
+                    PacketError::TimestampUnsynced => self.unsynced += 1,
+                    _ => self.expired += 1,
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Acknowledgement header for outgoing packets: the latest sequence number received and a
+    /// bitfield where bit `i` is set if `latest - 1 - i` has been received as well
+    pub fn ack_header(&self) -> (SequenceNumber, u32) {
+        match self.latest {
+            Some(latest) => (latest, (self.bitmap >> 1) as u32),
+            None => (SequenceNumber::default(), 0),
+        }
+    }
+
+    /// Latest sequence number received, if any
+    pub fn latest(&self) -> Option<SequenceNumber> {
+        self.latest
+    }
+
+    /// Number of packets accepted after a newer packet had already arrived
+    pub fn reordered(&self) -> u64 {
+        self.reordered
+    }
+
+    /// Number of packets rejected because they had already been received
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Number of packets rejected because they were older than the window
+    pub fn stale(&self) -> u64 {
+        self.stale
+    }
+
+    /// Number of packets rejected because their timestamp was too old
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    /// Number of packets rejected because their timestamp was too far in the future
+    pub fn premature(&self) -> u64 {
+        self.premature
+    }
+
+    /// Number of packets rejected because they were not stamped with the server's clock after
+    /// one that was
+    pub fn unsynced(&self) -> u64 {
+        self.unsynced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_in_order() {
+        let mut window = ReplayWindow::new();
+        for seq in 1..100 {
+            assert_eq!(window.accept(SequenceNumber(seq)), Acceptance::InOrder);
+        }
+        assert_eq!(window.latest(), Some(SequenceNumber(99)));
+        assert_eq!(window.reordered(), 0);
+        assert_eq!(window.duplicates(), 0);
+    }
+
+    #[test]
+    fn test_reordered() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.accept(SequenceNumber(1)), Acceptance::InOrder);
+        assert_eq!(window.accept(SequenceNumber(4)), Acceptance::InOrder);
+        assert_eq!(window.accept(SequenceNumber(3)), Acceptance::Reordered);
+        assert_eq!(window.accept(SequenceNumber(2)), Acceptance::Reordered);
+        assert_eq!(window.reordered(), 2);
+        assert_eq!(window.latest(), Some(SequenceNumber(4)));
+        assert_eq!(window.ack_header(), (SequenceNumber(4), 0b111));
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut window = ReplayWindow::new();
+        window.accept(SequenceNumber(1));
+        window.accept(SequenceNumber(3));
+        assert_eq!(window.accept(SequenceNumber(3)), Acceptance::Duplicate);
+        assert_eq!(window.accept(SequenceNumber(1)), Acceptance::Duplicate);
+        assert_eq!(window.accept(SequenceNumber(2)), Acceptance::Reordered);
+        assert_eq!(window.accept(SequenceNumber(2)), Acceptance::Duplicate);
+        assert_eq!(window.duplicates(), 3);
+    }
+
+    #[test]
+    fn test_stale() {
+        let mut window = ReplayWindow::new();
+        window.accept(SequenceNumber(1));
+        window.accept(SequenceNumber(200));
+        assert_eq!(window.accept(SequenceNumber(136)), Acceptance::Stale);
+        assert_eq!(window.accept(SequenceNumber(137)), Acceptance::Reordered);
+        assert_eq!(window.stale(), 1);
+    }
+
+    #[test]
+    fn test_replayed_packets() {
+        let mut window = ReplayWindow::new();
+        let bounds = TimestampBounds::default();
+        let now = Utc::now();
+        let packet = Packet::new(
+            SequenceNumber(1),
+            (SequenceNumber(0), 0),
+            Bytes::from_static(b"HELLO"),
+        )
+        .with_clock_synced(true);
+        assert_eq!(
+            window.accept_packet(&packet, &bounds, now).unwrap(),
+            Acceptance::InOrder
+        );
+        assert_eq!(
+            window.accept_packet(&packet, &bounds, now).unwrap(),
+            Acceptance::Duplicate
+        );
+
+        // replayed after the window moved on
+        for seq in 2..100 {
+            window.accept(SequenceNumber(seq));
+        }
+        let later = now + chrono::Duration::from_std(bounds.max_age * 2).unwrap();
+        match window.accept_packet(&packet, &bounds, later) {
+            Err(PacketError::TimestampExpired { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let early = packet.with_timestamp(later);
+        match window.accept_packet(&early, &bounds, now) {
+            Err(PacketError::TimestampInFuture { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(window.expired(), 1);
+        assert_eq!(window.premature(), 1);
+        assert_eq!(window.latest(), Some(SequenceNumber(99)));
+    }
+
+    #[test]
+    fn test_unsynced_clock() {
+        let mut window = ReplayWindow::new();
+        let bounds = TimestampBounds::default();
+        let now = Utc::now();
+        // a client whose clock is an hour behind, before and after synchronizing it
+        let skewed = now - chrono::Duration::hours(1);
+        let packet = |sequence| {
+            Packet::new(
+                SequenceNumber(sequence),
+                (SequenceNumber(0), 0),
+                Bytes::from_static(b"HELLO"),
+            )
+        };
+        let unsynced = packet(1).with_timestamp(skewed);
+        assert_eq!(
+            window.accept_packet(&unsynced, &bounds, now).unwrap(),
+            Acceptance::InOrder
+        );
+        let synced = packet(2).with_clock_synced(true);
+        assert_eq!(
+            window.accept_packet(&synced, &bounds, now).unwrap(),
+            Acceptance::InOrder
+        );
+        match window.accept_packet(&packet(3).with_timestamp(skewed), &bounds, now) {
+            Err(PacketError::TimestampUnsynced) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let expired = packet(4).with_timestamp(skewed).with_clock_synced(true);
+        match window.accept_packet(&expired, &bounds, now) {
+            Err(PacketError::TimestampExpired { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(window.expired(), 1);
+        assert_eq!(window.unsynced(), 1);
+        assert_eq!(window.latest(), Some(SequenceNumber(2)));
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let mut window = ReplayWindow::new();
+        let max = SequenceNumber(u32::MAX);
+        assert_eq!(window.accept(max.wrapping_sub(1)), Acceptance::InOrder);
+        assert_eq!(window.accept(SequenceNumber(1)), Acceptance::InOrder);
+        assert_eq!(window.accept(max), Acceptance::Reordered);
+        assert_eq!(window.accept(SequenceNumber(0)), Acceptance::Reordered);
+        assert_eq!(window.accept(max.wrapping_sub(1)), Acceptance::Duplicate);
+        assert_eq!(window.latest(), Some(SequenceNumber(1)));
+    }
+}